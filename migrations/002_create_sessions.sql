@@ -0,0 +1,11 @@
+CREATE TABLE sessions (
+                          id UUID PRIMARY KEY,
+                          user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                          user_agent TEXT,
+                          created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                          last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                          expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                          revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_sessions_user ON sessions(user_id);
@@ -0,0 +1,47 @@
+CREATE TABLE conversations (
+                               id UUID PRIMARY KEY,
+                               kind VARCHAR(16) NOT NULL,
+                               title VARCHAR(255),
+                               direct_key VARCHAR(73) UNIQUE,
+                               created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+                               created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE conversation_members (
+                                      conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+                                      user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                      role VARCHAR(16) NOT NULL,
+                                      joined_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                      PRIMARY KEY (conversation_id, user_id)
+);
+
+CREATE INDEX idx_conversation_members_user ON conversation_members(user_id);
+
+-- Existing one-to-one history becomes a direct conversation per pair of users.
+INSERT INTO conversations (id, kind, direct_key, created_at)
+SELECT gen_random_uuid(), 'direct', pair.direct_key, pair.created_at
+FROM (
+         SELECT LEAST(sender_id, receiver_id)::text || ':' || GREATEST(sender_id, receiver_id)::text AS direct_key,
+                MIN(created_at) AS created_at
+         FROM messages
+         GROUP BY 1
+     ) pair;
+
+INSERT INTO conversation_members (conversation_id, user_id, role, joined_at)
+SELECT c.id, split_part(c.direct_key, ':', 1)::uuid, 'member', c.created_at
+FROM conversations c
+WHERE c.kind = 'direct'
+UNION
+SELECT c.id, split_part(c.direct_key, ':', 2)::uuid, 'member', c.created_at
+FROM conversations c
+WHERE c.kind = 'direct';
+
+ALTER TABLE messages ADD COLUMN conversation_id UUID REFERENCES conversations(id) ON DELETE CASCADE;
+ALTER TABLE messages ALTER COLUMN receiver_id DROP NOT NULL;
+
+UPDATE messages m
+SET conversation_id = c.id
+FROM conversations c
+WHERE c.direct_key = LEAST(m.sender_id, m.receiver_id)::text || ':' || GREATEST(m.sender_id, m.receiver_id)::text;
+
+ALTER TABLE messages ALTER COLUMN conversation_id SET NOT NULL;
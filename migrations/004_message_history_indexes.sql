@@ -0,0 +1,2 @@
+CREATE INDEX idx_messages_conversation_keyset ON messages(conversation_id, created_at DESC, id DESC);
+CREATE INDEX idx_conversation_members_user_conversation ON conversation_members(user_id, conversation_id);
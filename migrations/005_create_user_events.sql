@@ -0,0 +1,9 @@
+CREATE TABLE user_events (
+                             seq BIGSERIAL PRIMARY KEY,
+                             user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                             event JSONB NOT NULL,
+                             created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_user_events_user_seq ON user_events(user_id, seq);
+CREATE INDEX idx_user_events_created_at ON user_events(created_at);
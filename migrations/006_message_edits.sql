@@ -0,0 +1,11 @@
+ALTER TABLE messages ADD COLUMN edited_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE messages ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+CREATE TABLE message_revisions (
+                                   id UUID PRIMARY KEY,
+                                   message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+                                   content TEXT NOT NULL,
+                                   created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_message_revisions_message ON message_revisions(message_id, created_at);
@@ -0,0 +1,5 @@
+ALTER TABLE messages ADD COLUMN delivered_at TIMESTAMP WITH TIME ZONE;
+
+UPDATE messages SET delivered_at = read_at WHERE read_at IS NOT NULL;
+
+CREATE INDEX idx_messages_receiver_unread ON messages(receiver_id) WHERE read_at IS NULL;
@@ -0,0 +1,14 @@
+ALTER TABLE messages ADD COLUMN content_tsv tsvector GENERATED ALWAYS AS (
+    to_tsvector(
+        'simple',
+        CASE
+            WHEN jsonb_typeof(content_type) = 'object' AND content_type ? 'File'
+                THEN coalesce(content_type->'File'->>'filename', '')
+            WHEN jsonb_typeof(content_type) = 'object'
+                THEN ''
+            ELSE content
+        END
+    )
+) STORED;
+
+CREATE INDEX idx_messages_content_tsv ON messages USING GIN (content_tsv);
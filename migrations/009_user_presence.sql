@@ -0,0 +1,6 @@
+CREATE TABLE user_presence (
+                               user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                               status VARCHAR(16) NOT NULL DEFAULT 'online',
+                               show_last_seen BOOLEAN NOT NULL DEFAULT TRUE,
+                               updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,29 @@
+CREATE TABLE contact_requests (
+                                  id UUID PRIMARY KEY,
+                                  requester_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                  addressee_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                  status VARCHAR(16) NOT NULL,
+                                  created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                  responded_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX idx_contact_requests_pending
+    ON contact_requests(requester_id, addressee_id) WHERE status = 'pending';
+CREATE INDEX idx_contact_requests_addressee ON contact_requests(addressee_id) WHERE status = 'pending';
+
+-- One row per direction, so a user's contacts are a single index lookup.
+CREATE TABLE contacts (
+                          user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                          contact_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                          created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                          PRIMARY KEY (user_id, contact_id)
+);
+
+CREATE TABLE blocked_users (
+                               blocker_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                               blocked_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                               created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                               PRIMARY KEY (blocker_id, blocked_id)
+);
+
+CREATE INDEX idx_blocked_users_blocked ON blocked_users(blocked_id);
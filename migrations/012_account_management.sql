@@ -0,0 +1,14 @@
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+-- Single-use tokens mailed to users. Only a hash of the token is stored.
+CREATE TABLE one_time_tokens (
+                                 token_hash VARCHAR(64) PRIMARY KEY,
+                                 user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                 purpose VARCHAR(32) NOT NULL,
+                                 payload TEXT,
+                                 created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                 expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                 used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_one_time_tokens_user ON one_time_tokens(user_id, purpose);
@@ -0,0 +1,19 @@
+-- A row without confirmed_at is an enrollment that has not been confirmed
+-- with a first code yet and does not affect login.
+CREATE TABLE user_totp (
+                           user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                           secret BYTEA NOT NULL,
+                           confirmed_at TIMESTAMP WITH TIME ZONE,
+                           last_used_step BIGINT,
+                           created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE recovery_codes (
+                                id UUID PRIMARY KEY,
+                                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                code_hash VARCHAR(64) NOT NULL,
+                                used_at TIMESTAMP WITH TIME ZONE,
+                                created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_recovery_codes_user ON recovery_codes(user_id);
@@ -0,0 +1,6 @@
+CREATE TABLE login_lockouts (
+                                user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                                failed_attempts INTEGER NOT NULL,
+                                last_failed_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                locked_until TIMESTAMP WITH TIME ZONE
+);
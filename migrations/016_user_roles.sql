@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN suspended_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE users ADD COLUMN suspension_reason TEXT;
+
+CREATE INDEX idx_users_suspended ON users(suspended_at) WHERE suspended_at IS NOT NULL;
@@ -0,0 +1,33 @@
+-- The reported content is copied so the report still shows what was said
+-- after the sender edits or deletes the message.
+CREATE TABLE reports (
+                         id UUID PRIMARY KEY,
+                         message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+                         conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+                         reporter_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                         sender_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                         message_content TEXT NOT NULL,
+                         reason TEXT NOT NULL,
+                         status VARCHAR(16) NOT NULL,
+                         created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                         resolved_at TIMESTAMP WITH TIME ZONE,
+                         resolved_by UUID REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE UNIQUE INDEX idx_reports_message_reporter ON reports(message_id, reporter_id);
+CREATE INDEX idx_reports_status ON reports(status, created_at);
+
+-- Append-only record of what admins did. Message ids are kept without a
+-- foreign key so the record outlives the message.
+CREATE TABLE moderation_actions (
+                                    id UUID PRIMARY KEY,
+                                    admin_id UUID REFERENCES users(id) ON DELETE SET NULL,
+                                    action VARCHAR(32) NOT NULL,
+                                    target_user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+                                    message_id UUID,
+                                    report_id UUID REFERENCES reports(id) ON DELETE SET NULL,
+                                    note TEXT,
+                                    created_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX idx_moderation_actions_created_at ON moderation_actions(created_at);
@@ -0,0 +1,7 @@
+CREATE TABLE message_reactions (
+                                   message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+                                   user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                   emoji VARCHAR(32) NOT NULL,
+                                   created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                   PRIMARY KEY (message_id, user_id, emoji)
+);
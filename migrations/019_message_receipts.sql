@@ -0,0 +1,15 @@
+-- Receipts per recipient, so every member of a group can have read a message.
+-- messages.delivered_at and read_at keep the first time any recipient got
+-- there, which for direct messages is the peer.
+CREATE TABLE message_receipts (
+                                  message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+                                  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                  delivered_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                  read_at TIMESTAMP WITH TIME ZONE,
+                                  PRIMARY KEY (message_id, user_id)
+);
+
+INSERT INTO message_receipts (message_id, user_id, delivered_at, read_at)
+SELECT id, receiver_id, delivered_at, read_at
+FROM messages
+WHERE receiver_id IS NOT NULL AND delivered_at IS NOT NULL;
@@ -0,0 +1,3 @@
+-- The one refresh token of a session that may still be redeemed. Sessions
+-- started before rotation have none until their next refresh.
+ALTER TABLE sessions ADD COLUMN refresh_token_id UUID;
@@ -0,0 +1,12 @@
+-- Lockouts are counted per account and client address, so failing logins
+-- from one address cannot lock the owner out from everywhere else.
+DROP TABLE login_lockouts;
+
+CREATE TABLE login_lockouts (
+                                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                                client_ip TEXT NOT NULL,
+                                failed_attempts INTEGER NOT NULL,
+                                last_failed_at TIMESTAMP WITH TIME ZONE NOT NULL,
+                                locked_until TIMESTAMP WITH TIME ZONE,
+                                PRIMARY KEY (user_id, client_ip)
+);
@@ -0,0 +1,11 @@
+-- message_receipts is the only record of delivery and reads; the per-message
+-- columns it replaced are derived from it when messages are loaded.
+INSERT INTO message_receipts (message_id, user_id, delivered_at, read_at)
+SELECT id, receiver_id, COALESCE(delivered_at, read_at), read_at
+FROM messages
+WHERE receiver_id IS NOT NULL AND COALESCE(delivered_at, read_at) IS NOT NULL
+ON CONFLICT (message_id, user_id) DO NOTHING;
+
+DROP INDEX idx_messages_receiver_unread;
+
+ALTER TABLE messages DROP COLUMN delivered_at, DROP COLUMN read_at;
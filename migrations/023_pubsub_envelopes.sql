@@ -0,0 +1,10 @@
+-- Envelopes too large for a NOTIFY payload. Only their id is notified and
+-- listeners load them from here; rows are pruned once every node has had
+-- ample time to read them.
+CREATE TABLE pubsub_envelopes (
+                                  id BIGSERIAL PRIMARY KEY,
+                                  envelope JSONB NOT NULL,
+                                  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_pubsub_envelopes_created_at ON pubsub_envelopes(created_at);
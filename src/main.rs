@@ -1,12 +1,19 @@
 mod realtime_messenger;
 
-use crate::realtime_messenger::{Auth, Handlers, Storage, WebSocketHandler};
+use crate::realtime_messenger::{
+    Auth, FileMailer, Handlers, InMemoryPubSub, Mailer, PostgresPubSub, PubSub, SmtpConfig, SmtpMailer,
+    Storage, WebSocketConfig, WebSocketHandler,
+};
 use sqlx::postgres::PgPoolOptions;
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::net::SocketAddr;
 use warp::Filter;
 use std::fs;
+use std::time::Duration;
+
+const EVENT_LOG_RETENTION_DAYS: i64 = 30;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -47,18 +54,95 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     sqlx::migrate!("./migrations").run(&pool).await?;
 
-    let auth = Auth::new(pool.clone());
+    let bind_address = env::var("BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+
+    let mailer: Arc<dyn Mailer> = match env::var("MAILER").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::new(SmtpConfig {
+            host: env::var("SMTP_HOST").expect("SMTP_HOST must be set"),
+            port: env_number("SMTP_PORT").map(|port| port as u16),
+            username: env::var("SMTP_USERNAME").ok(),
+            password: env::var("SMTP_PASSWORD").ok(),
+            from: env::var("MAIL_FROM").expect("MAIL_FROM must be set"),
+        }).expect("Invalid SMTP configuration")),
+        _ => Arc::new(FileMailer::new(
+            env::var("MAIL_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("storage/mail")),
+        )),
+    };
+    let public_url = env::var("PUBLIC_URL").unwrap_or_else(|_| format!("http://{}", bind_address));
+
+    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let auth = Auth::new(pool.clone(), jwt_secret, mailer, public_url);
     let storage = Storage::new(pool.clone(), storage_dir);
 
-    let ws_handler = WebSocketHandler::new(storage.clone());
-    let handlers = Handlers::new(auth, storage, ws_handler);
+    // Admins are appointed through configuration; there is no way to become
+    // one through the API.
+    if let Ok(emails) = env::var("ADMIN_EMAILS") {
+        let emails: Vec<String> = emails
+            .split(',')
+            .map(|email| email.trim().to_string())
+            .filter(|email| !email.is_empty())
+            .collect();
+        match storage.promote_admins(&emails).await {
+            Ok(promoted) => println!("Granted the admin role to {} account(s)", promoted),
+            Err(e) => println!("Failed to grant admin roles: {:?}", e),
+        }
+    }
+
+    let maintenance_storage = storage.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let cutoff = chrono::Utc::now() - chrono::Duration::days(EVENT_LOG_RETENTION_DAYS);
+            match maintenance_storage.prune_user_events(cutoff).await {
+                Ok(pruned) => println!("Pruned {} expired delivery events", pruned),
+                Err(e) => println!("Failed to prune delivery events: {:?}", e),
+            }
+        }
+    });
+
+    let pubsub: Arc<dyn PubSub> = match env::var("PUBSUB_BACKEND").as_deref() {
+        Ok("memory") => Arc::new(InMemoryPubSub::new()),
+        _ => Arc::new(PostgresPubSub::new(pool.clone())),
+    };
+
+    let mut ws_config = WebSocketConfig::default();
+    if let Some(secs) = env_number("WS_PING_INTERVAL_SECS") {
+        ws_config.ping_interval = Duration::from_secs(secs);
+    }
+    if let Some(secs) = env_number("WS_PONG_TIMEOUT_SECS") {
+        ws_config.pong_timeout = Duration::from_secs(secs);
+    }
+    if let Some(capacity) = env_number("WS_OUTBOUND_QUEUE_CAPACITY") {
+        ws_config.outbound_queue_capacity = capacity as usize;
+    }
+    if let Some(overflows) = env_number("WS_MAX_QUEUE_OVERFLOWS") {
+        ws_config.max_queue_overflows = overflows as u32;
+    }
+    if let Some(secs) = env_number("WS_IDLE_TIMEOUT_SECS") {
+        ws_config.idle_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = env_number("WS_TYPING_TIMEOUT_SECS") {
+        ws_config.typing_timeout = Duration::from_secs(secs);
+    }
+
+    let ws_handler = WebSocketHandler::new(storage.clone(), pubsub, ws_config);
+    // Comma separated addresses of reverse proxies allowed to say who the
+    // client is through X-Forwarded-For. Without any, the peer address is used.
+    let trusted_proxies = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| proxy.parse().expect("Invalid address in TRUSTED_PROXIES"))
+        .collect();
+    let handlers = Handlers::new(auth, storage, ws_handler).with_trusted_proxies(trusted_proxies);
 
     let api_routes = handlers.routes();
     let web_routes = realtime_messenger::ui::web::web_routes();
     let routes = api_routes.or(web_routes);
 
-    let addr: SocketAddr = env::var("BIND_ADDRESS")
-        .unwrap_or_else(|_| "127.0.0.1:8080".to_string())
+    let addr: SocketAddr = bind_address
         .parse()
         .expect("Invalid BIND_ADDRESS");
 
@@ -66,4 +150,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     warp::serve(routes).run(addr).await;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    env::var(name).ok().and_then(|value| value.parse().ok())
+}
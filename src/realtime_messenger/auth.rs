@@ -1,22 +1,136 @@
 use bcrypt::{hash, verify, DEFAULT_COST};
-use crate::realtime_messenger::models::User;
+use chrono::{DateTime, Duration, Utc};
+use crate::realtime_messenger::mailer::{Email, Mailer, MailerError};
+use crate::realtime_messenger::models::{Session, User};
+use crate::realtime_messenger::totp;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
 use uuid::Uuid;
 
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+const EMAIL_CHANGE_TOKEN_TTL_HOURS: i64 = 24;
+const EMAIL_VERIFICATION_TOKEN_TTL_HOURS: i64 = 48;
+const PASSWORD_RESET_TOKEN_TTL_MINUTES: i64 = 60;
+const LOGIN_CHALLENGE_TTL_MINUTES: i64 = 5;
+/// Failed logins allowed from one client before the account starts locking
+/// for it; each failure past that doubles the lock, from `LOCKOUT_BASE_SECS`
+/// up to `LOCKOUT_MAX_SECS`.
+const LOCKOUT_THRESHOLD: i32 = 5;
+const LOCKOUT_BASE_SECS: i64 = 30;
+const LOCKOUT_MAX_SECS: i64 = 3600;
+/// A failure this long after the previous one starts the count over.
+const LOCKOUT_RESET_HOURS: i64 = 24;
+const TOTP_ISSUER: &str = "Real-time Chat";
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// No 0/o or 1/l/i, so codes survive being read aloud or written down.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
 #[derive(Debug)]
 pub enum AuthError {
     InvalidCredentials,
+    InvalidToken,
+    TokenExpired,
+    SessionRevoked,
+    /// A refresh token was presented after it had already been exchanged, so
+    /// a copy of it is in someone else's hands. Its session has been revoked.
+    RefreshTokenReused { session_id: Uuid },
+    EmailTaken,
+    InvalidCode,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnabled,
+    AccountLocked { retry_after_secs: u64 },
+    AccountSuspended,
     DatabaseError(sqlx::Error),
     HashingError(bcrypt::BcryptError),
+    TokenError(jsonwebtoken::errors::Error),
+    MailError(MailerError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+    /// Proves the password step of a two-factor login; only redeemable for
+    /// tokens together with a code.
+    Challenge,
+}
+
+/// What a mailed one-time token may be redeemed for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OneTimeTokenPurpose {
+    EmailVerification,
+    EmailChange,
+    PasswordReset,
+}
+
+impl OneTimeTokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OneTimeTokenPurpose::EmailVerification => "email_verification",
+            OneTimeTokenPurpose::EmailChange => "email_change",
+            OneTimeTokenPurpose::PasswordReset => "password_reset",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub sid: Uuid,
+    pub kind: TokenKind,
+    pub iat: i64,
+    pub exp: i64,
+    /// Only on refresh tokens: which of the session's refresh tokens this is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<Uuid>,
+}
+
+pub enum LoginOutcome {
+    Authenticated(Box<User>, AuthTokens),
+    /// The password was right, but the account needs a second factor.
+    ChallengeIssued { challenge_token: String, expires_in: i64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32, for typing into an authenticator app by hand.
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
 }
 
 pub struct Auth {
     db_pool: PgPool,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    mailer: Arc<dyn Mailer>,
+    /// Where the web client is served, used to build links in emails.
+    public_url: String,
 }
 
 impl Auth {
-    pub fn new(db_pool: PgPool) -> Self {
-        Self { db_pool }
+    pub fn new(db_pool: PgPool, jwt_secret: String, mailer: Arc<dyn Mailer>, public_url: String) -> Self {
+        Self {
+            db_pool,
+            encoding_key: EncodingKey::from_secret(jwt_secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(jwt_secret.as_bytes()),
+            mailer,
+            public_url: public_url.trim_end_matches('/').to_string(),
+        }
     }
 
     pub async fn register_user(
@@ -24,7 +138,8 @@ impl Auth {
         username: String,
         email: String,
         password: String,
-    ) -> Result<User, AuthError> {
+        user_agent: Option<String>,
+    ) -> Result<(User, AuthTokens), AuthError> {
         let password_hash = hash(password.as_bytes(), DEFAULT_COST)
             .map_err(AuthError::HashingError)?;
 
@@ -33,7 +148,8 @@ impl Auth {
             r#"
             INSERT INTO users (id, username, email, password_hash, created_at, last_seen)
             VALUES ($1, $2, $3, $4, NOW(), NOW())
-            RETURNING *
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
             "#,
             Uuid::new_v4(),
             username,
@@ -44,29 +160,667 @@ impl Auth {
             .await
             .map_err(AuthError::DatabaseError)?;
 
-        Ok(user)
+        // The account is usable right away; a lost verification email can be
+        // sent again.
+        if let Err(e) = self.send_email_verification(&user).await {
+            eprintln!("Failed to send verification email to user {}: {:?}", user.id, e);
+        }
+
+        let tokens = self.create_session(user.id, user_agent).await?;
+
+        Ok((user, tokens))
+    }
+
+    /// Mails a link proving the user owns their current address. Earlier
+    /// verification links stop working.
+    pub async fn send_email_verification(&self, user: &User) -> Result<(), AuthError> {
+        self.revoke_one_time_tokens(user.id, OneTimeTokenPurpose::EmailVerification).await?;
+        let token = self.issue_one_time_token(
+            user.id,
+            OneTimeTokenPurpose::EmailVerification,
+            Some(user.email.clone()),
+            Duration::hours(EMAIL_VERIFICATION_TOKEN_TTL_HOURS),
+        ).await?;
+
+        self.send_email(Email {
+            to: user.email.clone(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nConfirm your email address by opening this link:\n{}/#verify={}\n\nThe link expires in {} hours.",
+                user.username, self.public_url, token, EMAIL_VERIFICATION_TOKEN_TTL_HOURS,
+            ),
+        }).await
+    }
+
+    /// Redeems a verification token. It only verifies the address it was
+    /// mailed to, so a token sent before an email change is worthless.
+    pub async fn verify_email(&self, token: &str) -> Result<User, AuthError> {
+        let (user_id, email) = self
+            .consume_one_time_token(token, OneTimeTokenPurpose::EmailVerification)
+            .await?;
+
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+            WHERE id = $1 AND email = $2 AND deleted_at IS NULL
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
+            "#,
+            user_id,
+            email,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidToken)
     }
 
+    /// Mails a password reset link if `email` belongs to an account. Unknown
+    /// addresses are silently ignored so the endpoint does not reveal which
+    /// accounts exist; for the same reason delivery failures are only logged.
+    pub async fn request_password_reset(&self, email: &str) -> Result<(), AuthError> {
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                   avatar_path, deleted_at, role, suspended_at, suspension_reason
+            FROM users
+            WHERE email = $1 AND deleted_at IS NULL
+            "#,
+            email
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        let Some(user) = user else {
+            return Ok(());
+        };
+
+        match self.send_password_reset(&user).await {
+            Err(AuthError::MailError(e)) => {
+                eprintln!("Failed to send password reset email to user {}: {}", user.id, e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Makes the user's current password useless, signs them out everywhere
+    /// and mails them a reset link. Returns the revoked session ids.
+    pub async fn force_password_reset(&self, user: &User) -> Result<Vec<Uuid>, AuthError> {
+        let password_hash = hash(Uuid::new_v4().simple().to_string().as_bytes(), DEFAULT_COST)
+            .map_err(AuthError::HashingError)?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2 WHERE id = $1",
+            user.id,
+            password_hash,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        let revoked = Self::revoke_sessions_in(&mut tx, user.id).await?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        self.send_password_reset(user).await?;
+        Ok(revoked)
+    }
+
+    /// Suspends the account and revokes its sessions. Returns the updated
+    /// user and the revoked session ids.
+    pub async fn suspend_user(&self, user_id: Uuid, reason: Option<String>) -> Result<(User, Vec<Uuid>), AuthError> {
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET suspended_at = COALESCE(suspended_at, NOW()), suspension_reason = $2
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
+            "#,
+            user_id,
+            reason,
+        )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidCredentials)?;
+
+        let revoked = Self::revoke_sessions_in(&mut tx, user_id).await?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok((user, revoked))
+    }
+
+    pub async fn unsuspend_user(&self, user_id: Uuid) -> Result<User, AuthError> {
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET suspended_at = NULL, suspension_reason = NULL
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
+            "#,
+            user_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidCredentials)
+    }
+
+    async fn send_password_reset(&self, user: &User) -> Result<(), AuthError> {
+        self.revoke_one_time_tokens(user.id, OneTimeTokenPurpose::PasswordReset).await?;
+        let token = self.issue_one_time_token(
+            user.id,
+            OneTimeTokenPurpose::PasswordReset,
+            None,
+            Duration::minutes(PASSWORD_RESET_TOKEN_TTL_MINUTES),
+        ).await?;
+
+        self.send_email(Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nChoose a new password by opening this link:\n{}/#reset={}\n\nThe link expires in {} minutes. If you did not ask for this, ignore this email.",
+                user.username, self.public_url, token, PASSWORD_RESET_TOKEN_TTL_MINUTES,
+            ),
+        }).await
+    }
+
+    /// Sets a new password with a reset token and revokes every session, since
+    /// whoever held them may have known the old password. Receiving the token
+    /// also proves ownership of the address. Returns the revoked session ids.
+    pub async fn reset_password(&self, token: &str, new_password: &str) -> Result<Vec<Uuid>, AuthError> {
+        let (user_id, _) = self
+            .consume_one_time_token(token, OneTimeTokenPurpose::PasswordReset)
+            .await?;
+
+        let password_hash = hash(new_password.as_bytes(), DEFAULT_COST)
+            .map_err(AuthError::HashingError)?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $2, email_verified_at = COALESCE(email_verified_at, NOW())
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id
+            "#,
+            user_id,
+            password_hash,
+        )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        let revoked = Self::revoke_sessions_in(&mut tx, user_id).await?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok(revoked)
+    }
+
+    /// Checks the password. Accounts with two-factor authentication get a
+    /// challenge token to complete with `complete_login_challenge` instead of
+    /// a session.
     pub async fn login(
         &self,
         email: String,
         password: String,
-    ) -> Result<User, AuthError> {
+        user_agent: Option<String>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<LoginOutcome, AuthError> {
         let user = sqlx::query_as!(
             User,
-            "SELECT * FROM users WHERE email = $1",
+            r#"
+            SELECT id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                   avatar_path, deleted_at, role, suspended_at, suspension_reason
+            FROM users
+            WHERE email = $1 AND deleted_at IS NULL
+            "#,
             email
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        let Some(user) = user else {
+            // Hashes anyway, so the response time does not tell which
+            // addresses have an account.
+            let _ = verify(password.as_bytes(), dummy_password_hash());
+            return Err(AuthError::InvalidCredentials);
+        };
+
+        // Counted before bcrypt, so a locked account costs no hashing and
+        // parallel guesses cannot all get in under the threshold.
+        let client = lockout_client(client_ip);
+        let lock_set = self.begin_login_attempt(user.id, &client).await?;
+        self.verify_password(&user, &password)?;
+        self.cancel_login_attempt(user.id, &client, lock_set).await?;
+
+        // Only told after the password checks out, so it reveals nothing to
+        // someone guessing.
+        if user.is_suspended() {
+            return Err(AuthError::AccountSuspended);
+        }
+
+        if self.is_two_factor_enabled(user.id).await? {
+            let ttl = Duration::minutes(LOGIN_CHALLENGE_TTL_MINUTES);
+            return Ok(LoginOutcome::ChallengeIssued {
+                challenge_token: self.encode_token(user.id, Uuid::nil(), TokenKind::Challenge, ttl, None)?,
+                expires_in: ttl.num_seconds(),
+            });
+        }
+
+        let tokens = self.start_session(&user, user_agent, &client).await?;
+        Ok(LoginOutcome::Authenticated(Box::new(user), tokens))
+    }
+
+    /// Second login step: exchanges a challenge token and a TOTP or recovery
+    /// code for a session.
+    pub async fn complete_login_challenge(
+        &self,
+        challenge_token: &str,
+        code: &str,
+        user_agent: Option<String>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(User, AuthTokens), AuthError> {
+        let claims = self.decode_token(challenge_token, TokenKind::Challenge)?;
+        let user = self.get_active_user(claims.sub).await?;
+
+        // Failed codes count towards the same lockout as failed passwords, and
+        // a correct password alone does not reset it.
+        let client = lockout_client(client_ip);
+        self.begin_login_attempt(user.id, &client).await?;
+        self.check_second_factor(user.id, code).await?;
+
+        let tokens = self.start_session(&user, user_agent, &client).await?;
+        Ok((user, tokens))
+    }
+
+    pub async fn is_two_factor_enabled(&self, user_id: Uuid) -> Result<bool, AuthError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL
+            ) AS "enabled!"
+            "#,
+            user_id
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)
+    }
+
+    /// Generates a new TOTP secret for the user. It only takes effect once
+    /// confirmed with a code; starting over replaces an unconfirmed secret.
+    pub async fn begin_totp_enrollment(&self, user: &User) -> Result<TotpEnrollment, AuthError> {
+        let secret = totp::generate_secret();
+
+        let stored = sqlx::query!(
+            r#"
+            INSERT INTO user_totp (user_id, secret, created_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, created_at = EXCLUDED.created_at, last_used_step = NULL
+            WHERE user_totp.confirmed_at IS NULL
+            RETURNING user_id
+            "#,
+            user.id,
+            secret,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        if stored.is_none() {
+            return Err(AuthError::TwoFactorAlreadyEnabled);
+        }
+
+        Ok(TotpEnrollment {
+            secret: totp::encode_base32(&secret),
+            otpauth_uri: totp::otpauth_uri(TOTP_ISSUER, &user.username, &secret),
+        })
+    }
+
+    /// Turns two-factor authentication on once the user proves their app
+    /// produces valid codes. Returns the recovery codes, which are only ever
+    /// shown this once.
+    pub async fn confirm_totp_enrollment(&self, user_id: Uuid, code: &str) -> Result<Vec<String>, AuthError> {
+        let secret = sqlx::query_scalar!(
+            "SELECT secret FROM user_totp WHERE user_id = $1 AND confirmed_at IS NULL",
+            user_id
         )
             .fetch_optional(&self.db_pool)
             .await
             .map_err(AuthError::DatabaseError)?
-            .ok_or(AuthError::InvalidCredentials)?;
+            .ok_or(AuthError::TwoFactorNotEnabled)?;
+
+        let step = totp::verify(&secret, code.trim(), Utc::now().timestamp() as u64)
+            .ok_or(AuthError::InvalidCode)?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!(
+            "UPDATE user_totp SET confirmed_at = NOW(), last_used_step = $2 WHERE user_id = $1",
+            user_id,
+            step as i64,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
 
+        let codes = Self::replace_recovery_codes(&mut tx, user_id).await?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok(codes)
+    }
+
+    /// Turns two-factor authentication off. Requires both the password and a
+    /// current code, so a stolen session alone cannot weaken the account.
+    pub async fn disable_two_factor(&self, user: &User, password: &str, code: &str) -> Result<(), AuthError> {
+        self.verify_password(user, password)?;
+        self.check_second_factor(user.id, code).await?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok(())
+    }
+
+    /// Invalidates all recovery codes and issues a fresh set.
+    pub async fn regenerate_recovery_codes(&self, user_id: Uuid, code: &str) -> Result<Vec<String>, AuthError> {
+        self.check_second_factor(user_id, code).await?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+        let codes = Self::replace_recovery_codes(&mut tx, user_id).await?;
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok(codes)
+    }
+
+    pub async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, AuthError> {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                   avatar_path, deleted_at, role, suspended_at, suspension_reason
+            FROM users
+            WHERE id = $1
+            "#,
+            user_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidCredentials)
+    }
+
+    /// Loads the user a token was issued to, refusing accounts that have been
+    /// deleted or suspended since. Sessions are revoked on both, but a token
+    /// must not outlive them even if one was missed.
+    async fn get_active_user(&self, user_id: Uuid) -> Result<User, AuthError> {
+        let user = self.get_user_by_id(user_id).await?;
+        if user.deleted_at.is_some() {
+            return Err(AuthError::InvalidToken);
+        }
+        if user.is_suspended() {
+            return Err(AuthError::AccountSuspended);
+        }
+        Ok(user)
+    }
+
+    pub fn verify_password(&self, user: &User, password: &str) -> Result<(), AuthError> {
         if !verify(password.as_bytes(), &user.password_hash)
             .map_err(AuthError::HashingError)? {
             return Err(AuthError::InvalidCredentials);
         }
 
+        Ok(())
+    }
+
+    /// Replaces the user's password after checking the current one, and revokes
+    /// every other session. Returns the revoked session ids so live connections
+    /// opened with them can be closed.
+    pub async fn change_password(
+        &self,
+        user: &User,
+        session_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<Vec<Uuid>, AuthError> {
+        self.verify_password(user, current_password)?;
+
+        let password_hash = hash(new_password.as_bytes(), DEFAULT_COST)
+            .map_err(AuthError::HashingError)?;
+
+        let mut tx = self.db_pool.begin().await.map_err(AuthError::DatabaseError)?;
+
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2 WHERE id = $1",
+            user.id,
+            password_hash,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        let rows = sqlx::query!(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL
+            RETURNING id
+            "#,
+            user.id,
+            session_id,
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        tx.commit().await.map_err(AuthError::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    /// Starts moving the account to `new_email`. The address only changes once
+    /// the link mailed to the new address is followed; any earlier pending
+    /// change is superseded.
+    pub async fn request_email_change(
+        &self,
+        user: &User,
+        password: &str,
+        new_email: &str,
+    ) -> Result<(), AuthError> {
+        self.verify_password(user, password)?;
+
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM users WHERE email = $1) AS "taken!""#,
+            new_email
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        if taken {
+            return Err(AuthError::EmailTaken);
+        }
+
+        self.revoke_one_time_tokens(user.id, OneTimeTokenPurpose::EmailChange).await?;
+        let token = self.issue_one_time_token(
+            user.id,
+            OneTimeTokenPurpose::EmailChange,
+            Some(new_email.to_string()),
+            Duration::hours(EMAIL_CHANGE_TOKEN_TTL_HOURS),
+        ).await?;
+
+        self.send_email(Email {
+            to: new_email.to_string(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Hi {},\n\nConfirm that this is your new email address by opening this link:\n{}/#confirm-email={}\n\nThe link expires in {} hours.",
+                user.username, self.public_url, token, EMAIL_CHANGE_TOKEN_TTL_HOURS,
+            ),
+        }).await
+    }
+
+    pub async fn confirm_email_change(&self, token: &str) -> Result<User, AuthError> {
+        let (user_id, new_email) = self
+            .consume_one_time_token(token, OneTimeTokenPurpose::EmailChange)
+            .await?;
+        let new_email = new_email.ok_or(AuthError::InvalidToken)?;
+
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET email = $2, email_verified_at = NOW()
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
+            "#,
+            user_id,
+            new_email,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(ref db) if db.is_unique_violation() => AuthError::EmailTaken,
+                e => AuthError::DatabaseError(e),
+            })?
+            .ok_or(AuthError::InvalidToken)
+    }
+
+    /// Validates an access token against its session and loads the user it was
+    /// issued to, returning the session id alongside.
+    pub async fn authenticate(&self, token: &str) -> Result<(User, Uuid), AuthError> {
+        let claims = self.decode_token(token, TokenKind::Access)?;
+        self.touch_session(claims.sid, claims.sub).await?;
+        let user = self.get_active_user(claims.sub).await?;
+        Ok((user, claims.sid))
+    }
+
+    /// Exchanges a valid refresh token for a fresh access/refresh pair and
+    /// extends the lifetime of the session it belongs to. Each refresh token
+    /// can be exchanged once; presenting one again revokes the session.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<AuthTokens, AuthError> {
+        let claims = self.decode_token(refresh_token, TokenKind::Refresh)?;
+        self.touch_session(claims.sid, claims.sub).await?;
+        self.get_active_user(claims.sub).await?;
+
+        let refresh_token_id = Uuid::new_v4();
+        let rotated = sqlx::query!(
+            r#"
+            UPDATE sessions SET refresh_token_id = $4, expires_at = $5
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+              AND refresh_token_id IS NOT DISTINCT FROM $3
+            RETURNING id
+            "#,
+            claims.sid,
+            claims.sub,
+            claims.jti,
+            refresh_token_id,
+            Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS),
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        if rotated.is_none() {
+            self.revoke_session(claims.sub, claims.sid).await?;
+            return Err(AuthError::RefreshTokenReused { session_id: claims.sid });
+        }
+
+        self.issue_tokens(claims.sub, claims.sid, refresh_token_id)
+    }
+
+    pub async fn logout(&self, session_id: Uuid) -> Result<(), AuthError> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL",
+            session_id
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(())
+    }
+
+    /// Revokes one of the user's sessions. Returns `false` if it did not belong
+    /// to the user or was already revoked.
+    pub async fn revoke_session(&self, user_id: Uuid, session_id: Uuid) -> Result<bool, AuthError> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            session_id,
+            user_id,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Revokes every active session of the user and returns their ids so live
+    /// connections opened with them can be closed.
+    pub async fn revoke_all_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>, AuthError> {
+        let rows = sqlx::query!(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            RETURNING id
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    pub async fn get_active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, AuthError> {
+        sqlx::query_as!(
+            Session,
+            r#"
+            SELECT id, user_id, user_agent, created_at, last_seen_at, expires_at, revoked_at
+            FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            ORDER BY last_seen_at DESC
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)
+    }
+
+    async fn start_session(&self, user: &User, user_agent: Option<String>, client: &str) -> Result<AuthTokens, AuthError> {
         sqlx::query!(
             "UPDATE users SET last_seen = NOW() WHERE id = $1",
             user.id
@@ -75,18 +829,538 @@ impl Auth {
             .await
             .map_err(AuthError::DatabaseError)?;
 
-        Ok(user)
+        sqlx::query!(
+            "DELETE FROM login_lockouts WHERE user_id = $1 AND client_ip = $2",
+            user.id,
+            client,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        self.create_session(user.id, user_agent).await
     }
 
-    pub async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, AuthError> {
-        sqlx::query_as!(
-            User,
-            "SELECT * FROM users WHERE id = $1",
+    /// Counts a login attempt from `client` as failed until it succeeds, and
+    /// locks the account for that client once too many have failed. Counting
+    /// and locking are one statement, so concurrent attempts each see the
+    /// attempts before them. Refused while the account is locked; otherwise
+    /// returns the lock this attempt set, if any.
+    async fn begin_login_attempt(&self, user_id: Uuid, client: &str) -> Result<Option<DateTime<Utc>>, AuthError> {
+        let counted = sqlx::query!(
+            r#"
+            INSERT INTO login_lockouts AS l (user_id, client_ip, failed_attempts, last_failed_at)
+            VALUES ($1, $2, 1, NOW())
+            ON CONFLICT (user_id, client_ip) DO UPDATE
+            SET failed_attempts = CASE
+                    WHEN l.last_failed_at < $3 THEN 1
+                    ELSE l.failed_attempts + 1
+                END,
+                locked_until = CASE
+                    WHEN l.last_failed_at >= $3 AND l.failed_attempts + 1 >= $4
+                    THEN NOW() + make_interval(secs => LEAST(
+                        $5::bigint << LEAST(l.failed_attempts + 1 - $4, 16),
+                        $6::bigint
+                    ))
+                END,
+                last_failed_at = NOW()
+            WHERE l.locked_until IS NULL OR l.locked_until <= NOW()
+            RETURNING failed_attempts, locked_until
+            "#,
+            user_id,
+            client,
+            Utc::now() - Duration::hours(LOCKOUT_RESET_HOURS),
+            LOCKOUT_THRESHOLD,
+            LOCKOUT_BASE_SECS,
+            LOCKOUT_MAX_SECS,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        match counted {
+            Some(attempt) => {
+                if attempt.locked_until.is_some() {
+                    println!(
+                        "Locking logins of user {} from {} after {} failed attempts",
+                        user_id, client, attempt.failed_attempts,
+                    );
+                }
+                Ok(attempt.locked_until)
+            }
+            None => {
+                let locked_until = sqlx::query_scalar!(
+                    "SELECT locked_until FROM login_lockouts WHERE user_id = $1 AND client_ip = $2",
+                    user_id,
+                    client,
+                )
+                    .fetch_optional(&self.db_pool)
+                    .await
+                    .map_err(AuthError::DatabaseError)?
+                    .flatten()
+                    .unwrap_or_else(Utc::now);
+
+                Err(AuthError::AccountLocked {
+                    retry_after_secs: (locked_until - Utc::now()).num_seconds().max(1) as u64,
+                })
+            }
+        }
+    }
+
+    /// Takes back an attempt counted by `begin_login_attempt` once its
+    /// password turned out right, along with the lock it set unless a later
+    /// failure has replaced it.
+    async fn cancel_login_attempt(
+        &self,
+        user_id: Uuid,
+        client: &str,
+        lock_set: Option<DateTime<Utc>>,
+    ) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            UPDATE login_lockouts
+            SET failed_attempts = failed_attempts - 1,
+                locked_until = CASE WHEN locked_until = $3 THEN NULL ELSE locked_until END
+            WHERE user_id = $1 AND client_ip = $2 AND failed_attempts > 0
+            "#,
+            user_id,
+            client,
+            lock_set,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(())
+    }
+
+    /// Accepts either a TOTP code or an unused recovery code. A TOTP code is
+    /// rejected if it, or a later one, was already used.
+    async fn check_second_factor(&self, user_id: Uuid, code: &str) -> Result<(), AuthError> {
+        let code: String = code
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_lowercase();
+
+        if code.len() == totp::DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+            let secret = sqlx::query_scalar!(
+                "SELECT secret FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL",
+                user_id
+            )
+                .fetch_optional(&self.db_pool)
+                .await
+                .map_err(AuthError::DatabaseError)?
+                .ok_or(AuthError::TwoFactorNotEnabled)?;
+
+            let step = totp::verify(&secret, &code, Utc::now().timestamp() as u64)
+                .ok_or(AuthError::InvalidCode)?;
+
+            sqlx::query!(
+                r#"
+                UPDATE user_totp SET last_used_step = $2
+                WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+                RETURNING user_id
+                "#,
+                user_id,
+                step as i64,
+            )
+                .fetch_optional(&self.db_pool)
+                .await
+                .map_err(AuthError::DatabaseError)?
+                .ok_or(AuthError::InvalidCode)?;
+
+            return Ok(());
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE recovery_codes SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            RETURNING id
+            "#,
+            user_id,
+            sha256_hex(&code),
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidCode)?;
+
+        Ok(())
+    }
+
+    async fn revoke_sessions_in(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: Uuid,
+    ) -> Result<Vec<Uuid>, AuthError> {
+        let rows = sqlx::query!(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            RETURNING id
+            "#,
             user_id
+        )
+            .fetch_all(&mut **tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    async fn replace_recovery_codes(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: Uuid,
+    ) -> Result<Vec<String>, AuthError> {
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut **tx)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+        for code in &codes {
+            sqlx::query!(
+                r#"
+                INSERT INTO recovery_codes (id, user_id, code_hash, created_at)
+                VALUES ($1, $2, $3, NOW())
+                "#,
+                Uuid::new_v4(),
+                user_id,
+                sha256_hex(&code.replace('-', "")),
+            )
+                .execute(&mut **tx)
+                .await
+                .map_err(AuthError::DatabaseError)?;
+        }
+
+        Ok(codes)
+    }
+
+    async fn create_session(&self, user_id: Uuid, user_agent: Option<String>) -> Result<AuthTokens, AuthError> {
+        let session_id = Uuid::new_v4();
+        let refresh_token_id = Uuid::new_v4();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (id, user_id, user_agent, created_at, last_seen_at, expires_at, refresh_token_id)
+            VALUES ($1, $2, $3, NOW(), NOW(), $4, $5)
+            "#,
+            session_id,
+            user_id,
+            user_agent,
+            Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS),
+            refresh_token_id,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        self.issue_tokens(user_id, session_id, refresh_token_id)
+    }
+
+    async fn touch_session(&self, session_id: Uuid, user_id: Uuid) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions SET last_seen_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING id
+            "#,
+            session_id,
+            user_id,
         )
             .fetch_optional(&self.db_pool)
             .await
             .map_err(AuthError::DatabaseError)?
-            .ok_or(AuthError::InvalidCredentials)
+            .ok_or(AuthError::SessionRevoked)?;
+
+        Ok(())
+    }
+
+    /// Creates a random single-use token and stores only its hash.
+    async fn issue_one_time_token(
+        &self,
+        user_id: Uuid,
+        purpose: OneTimeTokenPurpose,
+        payload: Option<String>,
+        ttl: Duration,
+    ) -> Result<String, AuthError> {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        sqlx::query!(
+            r#"
+            INSERT INTO one_time_tokens (token_hash, user_id, purpose, payload, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, NOW(), $5)
+            "#,
+            sha256_hex(&token),
+            user_id,
+            purpose.as_str(),
+            payload,
+            Utc::now() + ttl,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(token)
+    }
+
+    /// Invalidates the user's outstanding tokens for `purpose`.
+    async fn revoke_one_time_tokens(&self, user_id: Uuid, purpose: OneTimeTokenPurpose) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            UPDATE one_time_tokens SET used_at = NOW()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            purpose.as_str(),
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?;
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Marks a token as used and returns the user and payload it was issued
+    /// with. Unknown, expired and already used tokens are all rejected alike.
+    async fn consume_one_time_token(
+        &self,
+        token: &str,
+        purpose: OneTimeTokenPurpose,
+    ) -> Result<(Uuid, Option<String>), AuthError> {
+        let row = sqlx::query!(
+            r#"
+            UPDATE one_time_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id, payload
+            "#,
+            sha256_hex(token),
+            purpose.as_str(),
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        Ok((row.user_id, row.payload))
+    }
+
+    async fn send_email(&self, email: Email) -> Result<(), AuthError> {
+        self.mailer.send(email).await.map_err(AuthError::MailError)
+    }
+
+    fn issue_tokens(&self, user_id: Uuid, session_id: Uuid, refresh_token_id: Uuid) -> Result<AuthTokens, AuthError> {
+        let access_ttl = Duration::minutes(ACCESS_TOKEN_TTL_MINUTES);
+
+        Ok(AuthTokens {
+            access_token: self.encode_token(user_id, session_id, TokenKind::Access, access_ttl, None)?,
+            refresh_token: self.encode_token(
+                user_id,
+                session_id,
+                TokenKind::Refresh,
+                Duration::days(REFRESH_TOKEN_TTL_DAYS),
+                Some(refresh_token_id),
+            )?,
+            expires_in: access_ttl.num_seconds(),
+        })
+    }
+
+    fn encode_token(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+        kind: TokenKind,
+        ttl: Duration,
+        jti: Option<Uuid>,
+    ) -> Result<String, AuthError> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: user_id,
+            sid: session_id,
+            kind,
+            iat: now.timestamp(),
+            exp: (now + ttl).timestamp(),
+            jti,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(AuthError::TokenError)
+    }
+
+    fn decode_token(&self, token: &str, expected: TokenKind) -> Result<Claims, AuthError> {
+        let claims = decode::<Claims>(token, &self.decoding_key, &Validation::default())
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => AuthError::TokenExpired,
+                _ => AuthError::InvalidToken,
+            })?
+            .claims;
+
+        if claims.kind != expected {
+            return Err(AuthError::InvalidToken);
+        }
+
+        Ok(claims)
+    }
+}
+
+/// What login lockouts are counted against besides the account. Clients
+/// whose address is unknown share one count.
+fn lockout_client(client_ip: Option<IpAddr>) -> String {
+    client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// A hash of nothing in particular, checked against when a login names an
+/// unknown address so that it costs as much as a real one.
+fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash(Uuid::new_v4().as_bytes(), DEFAULT_COST).unwrap())
+}
+
+fn sha256_hex(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Formatted as two groups of five for readability; the dash is optional when
+/// the code is entered.
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..RECOVERY_CODE_LENGTH)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect();
+
+    format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_messenger::mailer::FileMailer;
+    use sqlx::postgres::PgPoolOptions;
+
+    const PASSWORD: &str = "correct horse battery";
+
+    /// Auth on the development database. Like the query macros, these tests
+    /// need `DATABASE_URL` to point at a migrated database.
+    async fn auth() -> Auth {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let db_pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
+        let mail_dir = std::env::temp_dir().join("messenger-tests").join("mail");
+        Auth::new(db_pool, "test-secret".to_string(), Arc::new(FileMailer::new(mail_dir)), "http://localhost".to_string())
+    }
+
+    async fn register(auth: &Auth) -> (User, AuthTokens) {
+        let username = format!("test_{}", Uuid::new_v4().simple());
+        auth.register_user(username.clone(), format!("{}@example.com", username), PASSWORD.to_string(), None)
+            .await
+            .unwrap()
+    }
+
+    async fn remove_user(auth: &Auth, user_id: Uuid) {
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&auth.db_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokens_stop_working_once_the_account_is_suspended() {
+        let auth = auth().await;
+        let (user, tokens) = register(&auth).await;
+        assert!(auth.authenticate(&tokens.access_token).await.is_ok());
+
+        // Suspended without revoking sessions, as a racing login would leave it.
+        sqlx::query!("UPDATE users SET suspended_at = NOW() WHERE id = $1", user.id)
+            .execute(&auth.db_pool)
+            .await
+            .unwrap();
+
+        assert!(matches!(auth.authenticate(&tokens.access_token).await, Err(AuthError::AccountSuspended)));
+        assert!(matches!(auth.refresh(&tokens.refresh_token).await, Err(AuthError::AccountSuspended)));
+
+        remove_user(&auth, user.id).await;
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_can_only_be_exchanged_once() {
+        let auth = auth().await;
+        let (user, tokens) = register(&auth).await;
+
+        let rotated = auth.refresh(&tokens.refresh_token).await.unwrap();
+        assert_ne!(rotated.refresh_token, tokens.refresh_token);
+
+        // The old token coming back means it was copied: the whole session goes.
+        assert!(matches!(
+            auth.refresh(&tokens.refresh_token).await,
+            Err(AuthError::RefreshTokenReused { .. })
+        ));
+        assert!(matches!(auth.refresh(&rotated.refresh_token).await, Err(AuthError::SessionRevoked)));
+        assert!(matches!(auth.authenticate(&rotated.access_token).await, Err(AuthError::SessionRevoked)));
+
+        remove_user(&auth, user.id).await;
+    }
+
+    #[tokio::test]
+    async fn parallel_guesses_cannot_get_past_the_lockout() {
+        let auth = Arc::new(auth().await);
+        let (user, _) = register(&auth).await;
+        let attacker: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let guesses: Vec<_> = (0..LOCKOUT_THRESHOLD * 3)
+            .map(|_| {
+                let auth = auth.clone();
+                let email = user.email.clone();
+                tokio::spawn(async move { auth.login(email, "wrong".to_string(), None, Some(attacker)).await })
+            })
+            .collect();
+
+        let mut checked = 0;
+        for guess in guesses {
+            match guess.await.unwrap() {
+                Err(AuthError::InvalidCredentials) => checked += 1,
+                Err(AuthError::AccountLocked { .. }) => {}
+                _ => panic!("unexpected login outcome"),
+            }
+        }
+        assert_eq!(checked, LOCKOUT_THRESHOLD);
+
+        // The owner, elsewhere, is not locked out by it.
+        let owner: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(auth.login(user.email.clone(), PASSWORD.to_string(), None, Some(owner)).await.is_ok());
+        assert!(matches!(
+            auth.login(user.email.clone(), PASSWORD.to_string(), None, Some(attacker)).await,
+            Err(AuthError::AccountLocked { .. })
+        ));
+
+        remove_user(&auth, user.id).await;
+    }
+
+    #[tokio::test]
+    async fn correct_passwords_do_not_count_towards_the_lockout() {
+        let auth = auth().await;
+        let (user, _) = register(&auth).await;
+
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            let _ = auth.login(user.email.clone(), "wrong".to_string(), None, None).await;
+        }
+        for _ in 0..3 {
+            assert!(auth.login(user.email.clone(), PASSWORD.to_string(), None, None).await.is_ok());
+        }
+
+        remove_user(&auth, user.id).await;
+    }
+
+    #[tokio::test]
+    async fn unknown_addresses_are_invalid_credentials() {
+        let auth = auth().await;
+        let email = format!("nobody_{}@example.com", Uuid::new_v4().simple());
+        assert!(matches!(
+            auth.login(email, PASSWORD.to_string(), None, None).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::OnceLock;
+use warp::ws::Message as WsMessage;
+
+/// Wire format of a WebSocket connection, chosen at handshake. JSON travels
+/// in text frames, MessagePack in binary frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Json,
+    MessagePack,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Codec::Json),
+            "msgpack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> WsMessage {
+        match self {
+            Codec::Json => WsMessage::text(serde_json::to_string(value).unwrap()),
+            Codec::MessagePack => WsMessage::binary(to_msgpack(value)),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, message: &WsMessage) -> Result<T, String> {
+        match self {
+            Codec::Json => {
+                let text = message.to_str().map_err(|_| "Expected a text frame".to_string())?;
+                serde_json::from_str(text).map_err(|e| e.to_string())
+            }
+            Codec::MessagePack => {
+                if !message.is_binary() {
+                    return Err("Expected a binary frame".to_string());
+                }
+                let mut deserializer = rmp_serde::Deserializer::new(message.as_bytes()).with_human_readable();
+                T::deserialize(&mut deserializer).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// An event on its way to possibly many connections. Events reach the fan-out
+/// as JSON; other encodings are produced on first use and shared by every
+/// connection that needs them.
+pub struct EncodedEvent {
+    json: String,
+    msgpack: OnceLock<Vec<u8>>,
+}
+
+impl EncodedEvent {
+    pub fn from_json(json: String) -> Self {
+        Self { json, msgpack: OnceLock::new() }
+    }
+
+    pub fn as_json(&self) -> &str {
+        &self.json
+    }
+
+    pub fn message(&self, codec: Codec) -> WsMessage {
+        match codec {
+            Codec::Json => WsMessage::text(self.json.clone()),
+            Codec::MessagePack => {
+                let bytes = self.msgpack.get_or_init(|| {
+                    let value: serde_json::Value = serde_json::from_str(&self.json).unwrap();
+                    to_msgpack(&value)
+                });
+                WsMessage::binary(bytes.clone())
+            }
+        }
+    }
+}
+
+/// Encodes with string ids and timestamps, the same shapes JSON clients see.
+fn to_msgpack<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut bytes)
+        .with_struct_map()
+        .with_human_readable();
+    value.serialize(&mut serializer).unwrap();
+    bytes
+}
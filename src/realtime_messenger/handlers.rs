@@ -4,11 +4,15 @@ use std::sync::Arc;
 use futures::{StreamExt, TryStreamExt};
 use uuid::Uuid;
 use super::{
-    auth::Auth,
-    models::{User, Message},
-    storage::Storage,
-    websocket::WebSocketHandler
+    auth::{Auth, AuthError, AuthTokens, LoginOutcome},
+    codec::Codec,
+    rate_limit::RateLimiter,
+    models::{AccountDeletionMode, ConversationKind, ModerationActionKind, Report, ReportStatus, User, Message, Session},
+    storage::{MessageCursor, MessagePage, ModerationTarget, SearchFilters, Storage, StorageError},
+    websocket::{negotiate_protocol, WebSocketHandler}
 };
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use warp::Buf;
 
 #[derive(Deserialize)]
@@ -24,16 +28,161 @@ pub struct RegisterRequest {
     password: String,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginChallengeRequest {
+    challenge_token: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorRequest {
+    password: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct SuspendUserRequest {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReportMessageRequest {
+    reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct DismissReportRequest {
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ActOnReportRequest {
+    #[serde(default)]
+    delete_message: bool,
+    #[serde(default)]
+    suspend_sender: bool,
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailRequest {
+    password: String,
+    new_email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    password: String,
+    mode: AccountDeletionMode,
+}
+
 #[derive(Deserialize)]
 pub struct SendMessageRequest {
     content: String,
-    receiver_id: Uuid,
+    #[serde(default)]
+    conversation_id: Option<Uuid>,
+    #[serde(default)]
+    receiver_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct EditMessageRequest {
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateConversationRequest {
+    kind: ConversationKind,
+    title: Option<String>,
+    #[serde(default)]
+    member_ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct AddMemberRequest {
+    user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    display_name: Option<String>,
+    bio: Option<String>,
+    avatar_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ContactRequestBody {
+    user_id: Option<Uuid>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BlockRequest {
+    user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct PresenceSettingsRequest {
+    show_last_seen: bool,
 }
 
 #[derive(Serialize)]
 pub struct LoginResponse {
     user_id: Uuid,
     user: User,
+    #[serde(flatten)]
+    tokens: AuthTokens,
+}
+
+#[derive(Serialize)]
+pub struct LoginChallengeResponse {
+    two_factor_required: bool,
+    challenge_token: String,
+    expires_in: i64,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    #[serde(flatten)]
+    session: Session,
+    current: bool,
 }
 
 #[derive(Debug)]
@@ -41,6 +190,9 @@ pub enum HandlerError {
     Auth(super::auth::AuthError),
     Storage(super::storage::StorageError),
     InvalidInput(String),
+    NotFound,
+    Forbidden,
+    RateLimited { retry_after_secs: u64 },
 }
 
 impl warp::reject::Reject for HandlerError {}
@@ -49,12 +201,21 @@ pub struct Handlers {
     auth: Arc<Auth>,
     storage: Arc<Storage>,
     ws_handler: Arc<WebSocketHandler>,
+    /// Shared by the unauthenticated auth endpoints, keyed by client IP.
+    ip_limiter: Arc<RateLimiter>,
+    /// Keyed by the account a request targets: an email address or user id.
+    account_limiter: Arc<RateLimiter>,
+    /// Reverse proxies whose `X-Forwarded-For` is believed about who the
+    /// client is. Requests from anywhere else are keyed by their peer address.
+    trusted_proxies: Arc<Vec<IpAddr>>,
 }
 
 #[derive(Deserialize)]
 struct WebSocketQuery {
-    #[serde(rename = "user-id")]
-    user_id: String,
+    token: String,
+    last_seq: Option<i64>,
+    protocol: Option<u32>,
+    encoding: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -63,20 +224,105 @@ struct MessageQuery {
     offset: i64,
 }
 
+#[derive(Deserialize)]
+struct HistoryQuery {
+    before: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct UserSearchQuery {
+    q: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct AdminUserQuery {
+    q: Option<String>,
+    suspended: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    status: Option<ReportStatus>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    user_id: Option<Uuid>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct PresenceQuery {
+    ids: String,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    from: Option<Uuid>,
+    peer: Option<Uuid>,
+    before: Option<chrono::DateTime<chrono::Utc>>,
+    after: Option<chrono::DateTime<chrono::Utc>>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+const DEFAULT_HISTORY_PAGE_SIZE: i64 = 50;
+const MAX_HISTORY_PAGE_SIZE: i64 = 200;
+const MAX_PRESENCE_IDS: usize = 200;
+const DEFAULT_USER_SEARCH_LIMIT: i64 = 20;
+const MAX_USER_SEARCH_LIMIT: i64 = 50;
+const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+const MAX_BIO_LENGTH: usize = 1000;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_EMAIL_LENGTH: usize = 255;
+const DEFAULT_ADMIN_PAGE_SIZE: i64 = 50;
+const MAX_ADMIN_PAGE_SIZE: i64 = 200;
+const ADMIN_SLOWEST_CONNECTIONS: usize = 10;
+const MAX_REPORT_REASON_LENGTH: usize = 1000;
+const IP_RATE_LIMIT_BURST: u32 = 20;
+const IP_RATE_LIMIT_REFILL: Duration = Duration::from_secs(3);
+const ACCOUNT_RATE_LIMIT_BURST: u32 = 5;
+const ACCOUNT_RATE_LIMIT_REFILL: Duration = Duration::from_secs(30);
+
 impl Handlers {
     pub fn new(auth: Auth, storage: Storage, ws_handler: WebSocketHandler) -> Self {
         Self {
             auth: Arc::new(auth),
             storage: Arc::new(storage),
             ws_handler: Arc::new(ws_handler),
+            ip_limiter: Arc::new(RateLimiter::new(IP_RATE_LIMIT_BURST, IP_RATE_LIMIT_REFILL)),
+            account_limiter: Arc::new(RateLimiter::new(ACCOUNT_RATE_LIMIT_BURST, ACCOUNT_RATE_LIMIT_REFILL)),
+            trusted_proxies: Arc::new(Vec::new()),
         }
     }
 
+    /// Behind a load balancer every request comes from the balancer, so
+    /// rate limits and login lockouts need the client address it forwards.
+    pub fn with_trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = Arc::new(proxies);
+        self
+    }
+
     pub(crate) fn routes(&self) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
         let api = self
             .auth_routes()
+            .or(self.account_routes())
             .or(self.message_routes())
+            .or(self.conversation_routes())
+            .or(self.search_routes())
+            .or(self.presence_routes())
             .or(self.user_routes())
+            .or(self.contact_routes())
+            .or(self.admin_routes())
             .or(self.file_routes())
             .or(self.serve_files())
             .or(self.ws_routes())
@@ -86,7 +332,7 @@ impl Handlers {
             .and(api)
             .with(warp::cors()
                 .allow_any_origin()
-                .allow_headers(vec!["content-type", "user-id", "content-length"])
+                .allow_headers(vec!["content-type", "authorization", "content-length"])
                 .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
                 .allow_credentials(true)
                 .max_age(3600))
@@ -97,7 +343,7 @@ impl Handlers {
         let upload = warp::path("upload")
             .and(warp::post())
             .and(warp::multipart::form().max_length(50_000_000))
-            .and(warp::header("user-id"))
+            .and(with_user(self.auth.clone()))
             .and(with_storage(self.storage.clone()))
             .and_then(Self::handle_file_upload);
 
@@ -106,11 +352,10 @@ impl Handlers {
 
     async fn handle_file_upload(
         mut form: warp::multipart::FormData,
-        user_id: String,
+        user: User,
         storage: Arc<Storage>,
     ) -> Result<impl Reply, Rejection> {
-        let user_id = Uuid::parse_str(&user_id)
-            .map_err(|_| warp::reject::custom(HandlerError::InvalidInput("Invalid user ID".to_string())))?;
+        let user_id = user.id;
         println!("Starting file upload for user: {}", user_id);
 
         while let Some(Ok(part)) = form.next().await {
@@ -164,177 +409,1702 @@ impl Handlers {
     fn auth_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
         let login = warp::path!("auth" / "login")
             .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
             .and(warp::body::json())
+            .and(warp::header::optional::<String>("user-agent"))
+            .and(client_ip(self.trusted_proxies.clone()))
             .and(with_auth(self.auth.clone()))
+            .and(with_rate_limiter(self.account_limiter.clone()))
             .and_then(Self::handle_login)
             .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
         let register = warp::path!("auth" / "register")
             .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
             .and(warp::body::json())
+            .and(warp::header::optional::<String>("user-agent"))
             .and(with_auth(self.auth.clone()))
             .and_then(Self::handle_register)
             .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        login.or(register).unify().boxed()
-    }
+        let refresh = warp::path!("auth" / "refresh")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_refresh)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    fn user_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
-        warp::path!("users")
-            .and(warp::get())
-            .and(with_storage(self.storage.clone()))
-            .and_then(Self::handle_get_users)
-            .boxed()
-    }
+        let login_challenge = warp::path!("auth" / "2fa")
+            .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
+            .and(warp::body::json())
+            .and(warp::header::optional::<String>("user-agent"))
+            .and(client_ip(self.trusted_proxies.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_login_challenge)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    fn message_routes(&self) -> BoxedFilter<(impl Reply,)> {
-        let get_messages = warp::path!("messages")
-            .and(warp::get())
-            .and(warp::query::<MessageQuery>())
-            .and(warp::header("user-id"))
-            .and(with_storage(self.storage.clone()))
-            .and_then(Self::handle_get_messages);
+        let verify_email = warp::path!("auth" / "verify")
+            .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
+            .and(warp::body::json())
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_verify_email)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        let send_message = warp::path!("messages")
+        let resend_verification = warp::path!("auth" / "verify" / "resend")
+            .and(warp::post())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_rate_limiter(self.account_limiter.clone()))
+            .and_then(Self::handle_resend_verification)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let forgot_password = warp::path!("auth" / "forgot")
             .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
             .and(warp::body::json())
-            .and(warp::header("user-id"))
-            .and(with_storage(self.storage.clone()))
-            .and_then(Self::handle_send_message);
+            .and(with_auth(self.auth.clone()))
+            .and(with_rate_limiter(self.account_limiter.clone()))
+            .and_then(Self::handle_forgot_password)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        get_messages.or(send_message).boxed()
-    }
+        let reset_password = warp::path!("auth" / "reset")
+            .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
+            .and(warp::body::json())
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_reset_password)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    fn ws_routes(&self) -> BoxedFilter<(impl Reply,)> {
-        warp::path!("ws")
-            .and(warp::ws())
-            .and(warp::query::<WebSocketQuery>())
+        let logout = warp::path!("auth" / "logout")
+            .and(warp::post())
+            .and(with_session(self.auth.clone()))
             .and(with_auth(self.auth.clone()))
             .and(with_ws_handler(self.ws_handler.clone()))
-            .and_then(Self::handle_ws_upgrade)
+            .and_then(Self::handle_logout)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let list_sessions = warp::path!("auth" / "sessions")
+            .and(warp::get())
+            .and(with_session(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_get_sessions)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let revoke_session = warp::path!("auth" / "sessions" / Uuid)
+            .and(warp::delete())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_revoke_session)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let revoke_all_sessions = warp::path!("auth" / "sessions")
+            .and(warp::delete())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_revoke_all_sessions)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        login
+            .or(login_challenge).unify()
+            .or(register).unify()
+            .or(refresh).unify()
+            .or(verify_email).unify()
+            .or(resend_verification).unify()
+            .or(forgot_password).unify()
+            .or(reset_password).unify()
+            .or(logout).unify()
+            .or(list_sessions).unify()
+            .or(revoke_session).unify()
+            .or(revoke_all_sessions).unify()
             .boxed()
     }
 
-    async fn handle_login(
-        req: LoginRequest,
-        auth: Arc<Auth>,
-    ) -> Result<impl Reply, Rejection> {
-        match auth.login(req.email, req.password).await {
-            Ok(user) => Ok(warp::reply::json(&LoginResponse {
-                user_id: user.id,
-                user,
-            })),
-            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
-        }
-    }
+    fn account_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let change_password = warp::path!("account" / "password")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_session(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_change_password)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    async fn handle_get_users(
-        storage: Arc<Storage>,
-    ) -> Result<Box<dyn Reply>, Rejection> {
-        let users = storage.get_users().await
-            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
-        println!("\n");
-        println!("users: {:?}", users);
-        println!("\n");
-        Ok(Box::new(warp::reply::json(&users)))
-    }
+        let change_email = warp::path!("account" / "email")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_rate_limiter(self.account_limiter.clone()))
+            .and_then(Self::handle_change_email)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    async fn handle_register(
-        req: RegisterRequest,
-        auth: Arc<Auth>,
-    ) -> Result<impl Reply, Rejection> {
-        match auth.register_user(req.username, req.email, req.password).await {
-            Ok(user) => Ok(warp::reply::json(&LoginResponse {
-                user_id: user.id,
-                user,
-            })),
-            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
-        }
+        // Confirmation comes from the link in the email, so it needs no login.
+        let confirm_email = warp::path!("account" / "email" / "confirm")
+            .and(warp::post())
+            .and(rate_limit(self.ip_limiter.clone(), self.trusted_proxies.clone()))
+            .and(warp::body::json())
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_confirm_email)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let delete_account = warp::path!("account")
+            .and(warp::delete())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_delete_account)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        change_password
+            .or(change_email).unify()
+            .or(confirm_email).unify()
+            .or(delete_account).unify()
+            .or(self.two_factor_routes()).unify()
+            .boxed()
     }
 
-    async fn handle_send_message(
-        req: SendMessageRequest,
-        user_id: String,
-        storage: Arc<Storage>,
-    ) -> Result<impl Reply, Rejection> {
-        let sender_id = Uuid::parse_str(&user_id)
-            .map_err(|_| warp::reject::custom(HandlerError::InvalidInput("Invalid user ID".to_string())))?;
+    fn two_factor_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let status = warp::path!("account" / "2fa")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_get_two_factor)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        let message = Message {
-            id: Uuid::new_v4(),
-            sender_id,
-            receiver_id: req.receiver_id,
-            content: req.content,
-            content_type: super::models::MessageType::Text,
-            created_at: chrono::Utc::now(),
-            read_at: None,
-        };
+        let setup = warp::path!("account" / "2fa" / "setup")
+            .and(warp::post())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_setup_two_factor)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        match storage.save_message(&message).await {
-            Ok(_) => Ok(warp::reply::json(&message)),
-            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
-        }
-    }
+        let confirm = warp::path!("account" / "2fa" / "confirm")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_confirm_two_factor)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-    async fn handle_get_messages(
-        query: MessageQuery,
-        user_id: String,
-        storage: Arc<Storage>,
-    ) -> Result<impl Reply, Rejection> {
-        let user_id = Uuid::parse_str(&user_id)
-            .map_err(|_| warp::reject::custom(HandlerError::InvalidInput("Invalid user ID".to_string())))?;
+        let disable = warp::path!("account" / "2fa")
+            .and(warp::delete())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_disable_two_factor)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        let messages = storage.get_user_messages(user_id, query.limit, query.offset).await;
+        let recovery_codes = warp::path!("account" / "2fa" / "recovery-codes")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and_then(Self::handle_regenerate_recovery_codes)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        match messages {
-            Ok(msgs) => Ok(warp::reply::json(&msgs)),
-            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
-        }
+        status
+            .or(setup).unify()
+            .or(confirm).unify()
+            .or(disable).unify()
+            .or(recovery_codes).unify()
+            .boxed()
     }
 
-    async fn handle_ws_upgrade(
-        ws: warp::ws::Ws,
-        query: WebSocketQuery,
-        auth: Arc<Auth>,
-        handler: Arc<WebSocketHandler>,
-    ) -> Result<impl Reply, Rejection> {
-        let user_id = Uuid::parse_str(&query.user_id)
-            .map_err(|_| warp::reject::custom(HandlerError::InvalidInput("Invalid user ID".to_string())))?;
+    fn admin_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let list_users = warp::path!("admin" / "users")
+            .and(warp::get())
+            .and(warp::query::<AdminUserQuery>())
+            .and(with_admin(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_admin_list_users)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
 
-        let user = auth.get_user_by_id(user_id).await
+        let suspend = warp::path!("admin" / "users" / Uuid / "suspend")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_admin(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_admin_suspend_user)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let unsuspend = warp::path!("admin" / "users" / Uuid / "unsuspend")
+            .and(warp::post())
+            .and(with_admin(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_admin_unsuspend_user)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let reset_password = warp::path!("admin" / "users" / Uuid / "reset-password")
+            .and(warp::post())
+            .and(with_admin(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_admin_reset_password)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let stats = warp::path!("admin" / "stats")
+            .and(warp::get())
+            .and(with_admin(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_admin_stats)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let reports = warp::path!("admin" / "reports")
+            .and(warp::get())
+            .and(warp::query::<ReportQuery>())
+            .and(with_admin(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_admin_list_reports)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let dismiss_report = warp::path!("admin" / "reports" / Uuid / "dismiss")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_admin(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_admin_dismiss_report)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let act_on_report = warp::path!("admin" / "reports" / Uuid / "act")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_admin(self.auth.clone()))
+            .and(with_auth(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_admin_act_on_report)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let audit = warp::path!("admin" / "audit")
+            .and(warp::get())
+            .and(warp::query::<AuditQuery>())
+            .and(with_admin(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_admin_audit_log)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        list_users
+            .or(suspend).unify()
+            .or(unsuspend).unify()
+            .or(reset_password).unify()
+            .or(stats).unify()
+            .or(reports).unify()
+            .or(dismiss_report).unify()
+            .or(act_on_report).unify()
+            .or(audit).unify()
+            .boxed()
+    }
+
+    fn user_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let search = warp::path!("users" / "search")
+            .and(warp::get())
+            .and(warp::query::<UserSearchQuery>())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_search_users)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let profile = warp::path!("users" / Uuid)
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_profile)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let own_profile = warp::path!("profile")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .map(|user: User| Box::new(warp::reply::json(&user)) as Box<dyn Reply>);
+
+        let update_profile = warp::path!("profile")
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_update_profile)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        search
+            .or(profile).unify()
+            .or(own_profile).unify()
+            .or(update_profile).unify()
+            .boxed()
+    }
+
+    fn contact_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let list = warp::path!("contacts")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_contacts)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let remove = warp::path!("contacts" / Uuid)
+            .and(warp::delete())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_remove_contact)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let requests = warp::path!("contacts" / "requests")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_contact_requests)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let send_request = warp::path!("contacts" / "requests")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_send_contact_request)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let accept = warp::path!("contacts" / "requests" / Uuid / "accept")
+            .and(warp::post())
+            .map(|request_id| (request_id, true))
+            .untuple_one()
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_respond_to_contact_request)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let decline = warp::path!("contacts" / "requests" / Uuid / "decline")
+            .and(warp::post())
+            .map(|request_id| (request_id, false))
+            .untuple_one()
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_respond_to_contact_request)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let blocked = warp::path!("blocks")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_blocked_users)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let block = warp::path!("blocks")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_block_user)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let unblock = warp::path!("blocks" / Uuid)
+            .and(warp::delete())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_unblock_user)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        list
+            .or(remove).unify()
+            .or(requests).unify()
+            .or(send_request).unify()
+            .or(accept).unify()
+            .or(decline).unify()
+            .or(blocked).unify()
+            .or(block).unify()
+            .or(unblock).unify()
+            .boxed()
+    }
+
+    fn message_routes(&self) -> BoxedFilter<(impl Reply,)> {
+        let get_messages = warp::path!("messages")
+            .and(warp::get())
+            .and(warp::query::<MessageQuery>())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_messages);
+
+        let send_message = warp::path!("messages")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_send_message);
+
+        let edit_message = warp::path!("messages" / Uuid)
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_edit_message);
+
+        let delete_message = warp::path!("messages" / Uuid)
+            .and(warp::delete())
+            .and(with_user(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_delete_message);
+
+        let revisions = warp::path!("messages" / Uuid / "revisions")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_revisions);
+
+        let receipts = warp::path!("messages" / Uuid / "receipts")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_receipts);
+
+        let report = warp::path!("messages" / Uuid / "report")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_report_message);
+
+        get_messages
+            .or(send_message)
+            .or(edit_message)
+            .or(delete_message)
+            .or(revisions)
+            .or(receipts)
+            .or(report)
+            .boxed()
+    }
+
+    fn conversation_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let list = warp::path!("conversations")
+            .and(warp::get())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_conversations)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let create = warp::path!("conversations")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_create_conversation)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let join = warp::path!("conversations" / Uuid / "join")
+            .and(warp::post())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_join_conversation)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let leave = warp::path!("conversations" / Uuid / "leave")
+            .and(warp::post())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_leave_conversation)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let add_member = warp::path!("conversations" / Uuid / "members")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_add_member)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let history = warp::path!("conversations" / Uuid / "messages")
+            .and(warp::get())
+            .and(warp::query::<HistoryQuery>())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_get_conversation_messages)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        list
+            .or(create).unify()
+            .or(history).unify()
+            .or(join).unify()
+            .or(leave).unify()
+            .or(add_member).unify()
+            .boxed()
+    }
+
+    fn search_routes(&self) -> BoxedFilter<(impl Reply,)> {
+        warp::path!("search")
+            .and(warp::get())
+            .and(warp::query::<SearchQuery>())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_search)
+            .boxed()
+    }
+
+    fn presence_routes(&self) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let snapshot = warp::path!("presence")
+            .and(warp::get())
+            .and(warp::query::<PresenceQuery>())
+            .and(with_user(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_get_presence)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        let settings = warp::path!("presence" / "settings")
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(with_user(self.auth.clone()))
+            .and(with_storage(self.storage.clone()))
+            .and_then(Self::handle_update_presence_settings)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>);
+
+        snapshot
+            .or(settings).unify()
+            .boxed()
+    }
+
+    fn ws_routes(&self) -> BoxedFilter<(impl Reply,)> {
+        warp::path!("ws")
+            .and(warp::ws())
+            .and(warp::query::<WebSocketQuery>())
+            .and(with_auth(self.auth.clone()))
+            .and(with_ws_handler(self.ws_handler.clone()))
+            .and_then(Self::handle_ws_upgrade)
+            .boxed()
+    }
+
+    async fn handle_login(
+        req: LoginRequest,
+        user_agent: Option<String>,
+        client_ip: Option<IpAddr>,
+        auth: Arc<Auth>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<impl Reply, Rejection> {
+        check_rate_limit(&limiter, &format!("login:{}", req.email.trim().to_lowercase()))?;
+
+        match auth.login(req.email, req.password, user_agent, client_ip).await {
+            Ok(LoginOutcome::Authenticated(user, tokens)) => Ok(warp::reply::json(&LoginResponse {
+                user_id: user.id,
+                user: *user,
+                tokens,
+            })),
+            Ok(LoginOutcome::ChallengeIssued { challenge_token, expires_in }) => {
+                Ok(warp::reply::json(&LoginChallengeResponse {
+                    two_factor_required: true,
+                    challenge_token,
+                    expires_in,
+                }))
+            }
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_login_challenge(
+        req: LoginChallengeRequest,
+        user_agent: Option<String>,
+        client_ip: Option<IpAddr>,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.complete_login_challenge(&req.challenge_token, &req.code, user_agent, client_ip).await {
+            Ok((user, tokens)) => Ok(warp::reply::json(&LoginResponse {
+                user_id: user.id,
+                user,
+                tokens,
+            })),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_get_two_factor(
+        user: User,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.is_two_factor_enabled(user.id).await {
+            Ok(enabled) => Ok(warp::reply::json(&serde_json::json!({ "enabled": enabled }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_setup_two_factor(
+        user: User,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.begin_totp_enrollment(&user).await {
+            Ok(enrollment) => Ok(warp::reply::json(&enrollment)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_confirm_two_factor(
+        req: TwoFactorCodeRequest,
+        user: User,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.confirm_totp_enrollment(user.id, &req.code).await {
+            Ok(codes) => Ok(warp::reply::json(&serde_json::json!({ "recovery_codes": codes }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_disable_two_factor(
+        req: DisableTwoFactorRequest,
+        user: User,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.disable_two_factor(&user, &req.password, &req.code).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "enabled": false }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_regenerate_recovery_codes(
+        req: TwoFactorCodeRequest,
+        user: User,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.regenerate_recovery_codes(user.id, &req.code).await {
+            Ok(codes) => Ok(warp::reply::json(&serde_json::json!({ "recovery_codes": codes }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_admin_list_users(
+        query: AdminUserQuery,
+        _admin: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let terms = query.q.as_deref().map(str::trim).filter(|terms| !terms.is_empty());
+        let limit = query.limit.unwrap_or(DEFAULT_ADMIN_PAGE_SIZE).clamp(1, MAX_ADMIN_PAGE_SIZE);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        match storage.list_users_for_admin(terms, query.suspended, limit, offset).await {
+            Ok(users) => Ok(warp::reply::json(&users)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_admin_suspend_user(
+        user_id: Uuid,
+        req: SuspendUserRequest,
+        admin: User,
+        auth: Arc<Auth>,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let reason = req.reason.map(|reason| reason.trim().to_string()).filter(|reason| !reason.is_empty());
+        let user = Self::suspend_user(&admin, user_id, reason.clone(), &auth, &ws_handler).await?;
+
+        let target = ModerationTarget { user_id: Some(user_id), ..Default::default() };
+        Self::record_moderation_action(&storage, &admin, ModerationActionKind::SuspendUser, target, reason).await;
+        Ok(warp::reply::json(&user))
+    }
+
+    async fn handle_admin_unsuspend_user(
+        user_id: Uuid,
+        admin: User,
+        auth: Arc<Auth>,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let user = auth.unsuspend_user(user_id).await
+            .map_err(admin_target_error)?;
+
+        println!("Admin {} unsuspended user {}", admin.id, user_id);
+        let target = ModerationTarget { user_id: Some(user_id), ..Default::default() };
+        Self::record_moderation_action(&storage, &admin, ModerationActionKind::UnsuspendUser, target, None).await;
+        Ok(warp::reply::json(&user))
+    }
+
+    async fn handle_admin_reset_password(
+        user_id: Uuid,
+        admin: User,
+        auth: Arc<Auth>,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let target = auth.get_user_by_id(user_id).await
+            .map_err(admin_target_error)?;
+        if target.deleted_at.is_some() {
+            return Err(warp::reject::custom(HandlerError::NotFound));
+        }
+
+        let revoked = auth.force_password_reset(&target).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        println!("Admin {} forced a password reset for user {}", admin.id, user_id);
+        let target = ModerationTarget { user_id: Some(user_id), ..Default::default() };
+        Self::record_moderation_action(&storage, &admin, ModerationActionKind::ForcePasswordReset, target, None).await;
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": revoked })))
+    }
+
+    /// Suspends a user and drops their connections. Shared by the user admin
+    /// endpoint and report handling.
+    async fn suspend_user(
+        admin: &User,
+        user_id: Uuid,
+        reason: Option<String>,
+        auth: &Auth,
+        ws_handler: &WebSocketHandler,
+    ) -> Result<User, Rejection> {
+        let target = auth.get_user_by_id(user_id).await
+            .map_err(admin_target_error)?;
+
+        // Admins are demoted by hand, never locked out by each other.
+        if target.id == admin.id || target.is_admin() {
+            return Err(warp::reject::custom(HandlerError::Forbidden));
+        }
+
+        let (user, revoked) = auth.suspend_user(user_id, reason).await
+            .map_err(admin_target_error)?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        println!("Admin {} suspended user {}", admin.id, user_id);
+        Ok(user)
+    }
+
+    /// The action has already happened by the time it is recorded, so a
+    /// failure to write the audit entry is logged rather than returned.
+    async fn record_moderation_action(
+        storage: &Storage,
+        admin: &User,
+        action: ModerationActionKind,
+        target: ModerationTarget,
+        note: Option<String>,
+    ) {
+        if let Err(e) = storage.record_moderation_action(admin.id, action, target, note).await {
+            println!("Failed to record moderation action {} by admin {}: {:?}", action.as_str(), admin.id, e);
+        }
+    }
+
+    async fn handle_admin_list_reports(
+        query: ReportQuery,
+        _admin: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let status = query.status.unwrap_or(ReportStatus::Open);
+        let limit = query.limit.unwrap_or(DEFAULT_ADMIN_PAGE_SIZE).clamp(1, MAX_ADMIN_PAGE_SIZE);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        match storage.get_reports(status, limit, offset).await {
+            Ok(reports) => Ok(warp::reply::json(&reports)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_admin_dismiss_report(
+        report_id: Uuid,
+        req: DismissReportRequest,
+        admin: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let report = Self::open_report(&storage, report_id).await?;
+        let note = req.note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty());
+
+        let resolved = storage.resolve_reports(report.message_id, admin.id, ReportStatus::Dismissed).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        let target = ModerationTarget {
+            user_id: Some(report.sender_id),
+            message_id: Some(report.message_id),
+            report_id: Some(report.id),
+        };
+        Self::record_moderation_action(&storage, &admin, ModerationActionKind::DismissReport, target, note).await;
+        Ok(warp::reply::json(&resolved))
+    }
+
+    async fn handle_admin_act_on_report(
+        report_id: Uuid,
+        req: ActOnReportRequest,
+        admin: User,
+        auth: Arc<Auth>,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        if !req.delete_message && !req.suspend_sender {
+            return Err(warp::reject::custom(HandlerError::InvalidInput(
+                "Choose at least one action".to_string(),
+            )));
+        }
+
+        let report = Self::open_report(&storage, report_id).await?;
+        let note = req.note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty());
+        let target = ModerationTarget {
+            user_id: Some(report.sender_id),
+            message_id: Some(report.message_id),
+            report_id: Some(report.id),
+        };
+
+        if req.suspend_sender {
+            Self::suspend_user(&admin, report.sender_id, note.clone(), &auth, &ws_handler).await?;
+            Self::record_moderation_action(&storage, &admin, ModerationActionKind::SuspendUser, target, note.clone()).await;
+        }
+
+        if req.delete_message {
+            // The sender may have deleted it already, which is just as good.
+            match ws_handler.remove_message(report.message_id).await {
+                Ok(_) | Err(StorageError::NotFound) => {}
+                Err(e) => return Err(warp::reject::custom(HandlerError::Storage(e))),
+            }
+            Self::record_moderation_action(&storage, &admin, ModerationActionKind::DeleteMessage, target, note).await;
+        }
+
+        let resolved = storage.resolve_reports(report.message_id, admin.id, ReportStatus::Actioned).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        println!("Admin {} acted on report {}", admin.id, report.id);
+        Ok(warp::reply::json(&resolved))
+    }
+
+    async fn open_report(storage: &Storage, report_id: Uuid) -> Result<Report, Rejection> {
+        let report = storage.get_report(report_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        if report.status != ReportStatus::Open {
+            return Err(warp::reject::custom(HandlerError::Storage(StorageError::Conflict)));
+        }
+
+        Ok(report)
+    }
+
+    async fn handle_admin_audit_log(
+        query: AuditQuery,
+        _admin: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let limit = query.limit.unwrap_or(DEFAULT_ADMIN_PAGE_SIZE).clamp(1, MAX_ADMIN_PAGE_SIZE);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        match storage.get_moderation_actions(query.user_id, limit, offset).await {
+            Ok(actions) => Ok(warp::reply::json(&actions)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_admin_stats(
+        _admin: User,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let database = storage.get_server_stats().await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        let files = storage.get_storage_usage().await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        // Connections are only those on this node.
+        let mut connections = ws_handler.connection_stats().await;
+        let sockets = connections.len();
+        let users = connections
+            .iter()
+            .map(|connection| connection.user_id)
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        connections.truncate(ADMIN_SLOWEST_CONNECTIONS);
+
+        Ok(warp::reply::json(&serde_json::json!({
+            "connections": {
+                "sockets": sockets,
+                "users": users,
+                "slowest": connections,
+            },
+            "database": database,
+            "storage": files,
+        })))
+    }
+
+    async fn handle_search_users(
+        query: UserSearchQuery,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let terms = query.q.trim();
+        if terms.is_empty() {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Empty search query".to_string())));
+        }
+
+        let limit = query.limit.unwrap_or(DEFAULT_USER_SEARCH_LIMIT).clamp(1, MAX_USER_SEARCH_LIMIT);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        match storage.search_users(user.id, terms, limit, offset).await {
+            Ok(profiles) => Ok(warp::reply::json(&profiles)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_profile(
+        user_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.get_public_profile(user.id, user_id).await {
+            Ok(profile) => Ok(warp::reply::json(&profile)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_update_profile(
+        req: UpdateProfileRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let display_name = req.display_name.map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+        let bio = req.bio.map(|bio| bio.trim().to_string()).filter(|bio| !bio.is_empty());
+
+        if display_name.as_ref().is_some_and(|name| name.chars().count() > MAX_DISPLAY_NAME_LENGTH) {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Display name is too long".to_string())));
+        }
+        if bio.as_ref().is_some_and(|bio| bio.chars().count() > MAX_BIO_LENGTH) {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Bio is too long".to_string())));
+        }
+
+        // Avatars are files the user uploaded themself through /upload.
+        if let Some(avatar_path) = &req.avatar_path {
+            let filename = avatar_path.strip_prefix(&format!("/files/{}/", user.id));
+            if !filename.is_some_and(|name| !name.is_empty() && !name.contains('/') && name != "..") {
+                return Err(warp::reject::custom(HandlerError::InvalidInput("Invalid avatar path".to_string())));
+            }
+        }
+
+        match storage.update_profile(user.id, display_name, bio, req.avatar_path).await {
+            Ok(user) => Ok(warp::reply::json(&user)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_contacts(
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.get_contacts(user.id).await {
+            Ok(contacts) => Ok(warp::reply::json(&contacts)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_remove_contact(
+        contact_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.remove_contact(user.id, contact_id).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "removed": contact_id }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_contact_requests(
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.get_pending_contact_requests(user.id).await {
+            Ok(requests) => Ok(warp::reply::json(&requests)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_send_contact_request(
+        req: ContactRequestBody,
+        user: User,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let addressee_id = match (req.user_id, req.username) {
+            (Some(user_id), _) => user_id,
+            (None, Some(username)) => storage.get_user_id_by_username(username.trim()).await
+                .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?,
+            (None, None) => {
+                return Err(warp::reject::custom(HandlerError::InvalidInput(
+                    "Either user_id or username is required".to_string(),
+                )));
+            }
+        };
+
+        let request = storage.create_contact_request(user.id, addressee_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        ws_handler.notify_contact_request(&request).await;
+
+        Ok(warp::reply::json(&request))
+    }
+
+    async fn handle_respond_to_contact_request(
+        request_id: Uuid,
+        accept: bool,
+        user: User,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let request = storage.respond_to_contact_request(request_id, user.id, accept).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        ws_handler.notify_contact_request(&request).await;
+
+        Ok(warp::reply::json(&request))
+    }
+
+    async fn handle_get_blocked_users(
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.get_blocked_users(user.id).await {
+            Ok(blocked) => Ok(warp::reply::json(&blocked)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_block_user(
+        req: BlockRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.block_user(user.id, req.user_id).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "blocked": req.user_id }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_unblock_user(
+        blocked_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.unblock_user(user.id, blocked_id).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "unblocked": blocked_id }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_register(
+        req: RegisterRequest,
+        user_agent: Option<String>,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        let email = req.email.trim();
+        if !is_valid_email(email) {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Invalid email address".to_string())));
+        }
+        if req.password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Password is too short".to_string())));
+        }
+
+        match auth.register_user(req.username, email.to_string(), req.password, user_agent).await {
+            Ok((user, tokens)) => Ok(warp::reply::json(&LoginResponse {
+                user_id: user.id,
+                user,
+                tokens,
+            })),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_verify_email(
+        req: VerifyEmailRequest,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.verify_email(&req.token).await {
+            Ok(user) => Ok(warp::reply::json(&user)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_resend_verification(
+        user: User,
+        auth: Arc<Auth>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<impl Reply, Rejection> {
+        check_rate_limit(&limiter, &format!("mail:{}", user.id))?;
+
+        if user.email_verified_at.is_some() {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Email already verified".to_string())));
+        }
+
+        match auth.send_email_verification(&user).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "sent_to": user.email }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_forgot_password(
+        req: ForgotPasswordRequest,
+        auth: Arc<Auth>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<impl Reply, Rejection> {
+        check_rate_limit(&limiter, &format!("forgot:{}", req.email.trim().to_lowercase()))?;
+
+        // Same answer whether or not the address has an account.
+        match auth.request_password_reset(req.email.trim()).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "requested": true }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_reset_password(
+        req: ResetPasswordRequest,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        if req.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Password is too short".to_string())));
+        }
+
+        let revoked = auth.reset_password(&req.token, &req.new_password).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": revoked })))
+    }
+
+    async fn handle_refresh(
+        req: RefreshRequest,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.refresh(&req.refresh_token).await {
+            Ok(tokens) => Ok(warp::reply::json(&tokens)),
+            Err(e) => {
+                if let AuthError::RefreshTokenReused { session_id } = e {
+                    ws_handler.disconnect_sessions(&[session_id]).await;
+                }
+                Err(warp::reject::custom(HandlerError::Auth(e)))
+            }
+        }
+    }
+
+    async fn handle_logout(
+        _user: User,
+        session_id: Uuid,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        auth.logout(session_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+        ws_handler.disconnect_sessions(&[session_id]).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": [session_id] })))
+    }
+
+    async fn handle_get_sessions(
+        user: User,
+        session_id: Uuid,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        let sessions = auth.get_active_sessions(user.id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+
+        let sessions: Vec<SessionResponse> = sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: session.id == session_id,
+                session,
+            })
+            .collect();
+
+        Ok(warp::reply::json(&sessions))
+    }
+
+    async fn handle_revoke_session(
+        target_session_id: Uuid,
+        user: User,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let revoked = auth.revoke_session(user.id, target_session_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+
+        if !revoked {
+            return Err(warp::reject::custom(HandlerError::NotFound));
+        }
+        ws_handler.disconnect_sessions(&[target_session_id]).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": [target_session_id] })))
+    }
+
+    async fn handle_revoke_all_sessions(
+        user: User,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let revoked = auth.revoke_all_sessions(user.id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": revoked })))
+    }
+
+    async fn handle_change_password(
+        req: ChangePasswordRequest,
+        user: User,
+        session_id: Uuid,
+        auth: Arc<Auth>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        if req.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Password is too short".to_string())));
+        }
+
+        let revoked = auth.change_password(&user, session_id, &req.current_password, &req.new_password).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "revoked": revoked })))
+    }
+
+    async fn handle_change_email(
+        req: ChangeEmailRequest,
+        user: User,
+        auth: Arc<Auth>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<impl Reply, Rejection> {
+        check_rate_limit(&limiter, &format!("mail:{}", user.id))?;
+
+        let new_email = req.new_email.trim();
+        if !is_valid_email(new_email) {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Invalid email address".to_string())));
+        }
+
+        auth.request_email_change(&user, &req.password, new_email).await
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+
+        Ok(warp::reply::json(&serde_json::json!({ "pending_email": new_email })))
+    }
+
+    async fn handle_confirm_email(
+        req: ConfirmEmailRequest,
+        auth: Arc<Auth>,
+    ) -> Result<impl Reply, Rejection> {
+        match auth.confirm_email_change(&req.token).await {
+            Ok(user) => Ok(warp::reply::json(&user)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Auth(e))),
+        }
+    }
+
+    async fn handle_delete_account(
+        req: DeleteAccountRequest,
+        user: User,
+        auth: Arc<Auth>,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        auth.verify_password(&user, &req.password)
+            .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
+
+        let revoked = storage.delete_account(user.id, req.mode).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        ws_handler.disconnect_sessions(&revoked).await;
+
+        Ok(warp::reply::json(&serde_json::json!({ "deleted": user.id })))
+    }
+
+    async fn handle_send_message(
+        req: SendMessageRequest,
+        user: User,
+        storage: Arc<Storage>,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let sender_id = user.id;
+
+        let conversation = storage
+            .resolve_conversation(sender_id, req.conversation_id, req.receiver_id)
+            .await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        let message = Message {
+            id: Uuid::new_v4(),
+            conversation_id: conversation.id,
+            sender_id,
+            receiver_id: conversation.direct_peer(sender_id),
+            content: req.content,
+            content_type: super::models::MessageType::Text,
+            created_at: chrono::Utc::now(),
+            delivered_at: None,
+            read_at: None,
+            edited_at: None,
+            deleted_at: None,
+            reactions: Vec::new(),
+        };
+
+        storage.save_message(&message).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        ws_handler.deliver_new_message(&conversation, message.clone(), None).await;
+
+        Ok(warp::reply::json(&message))
+    }
+
+    async fn handle_edit_message(
+        message_id: Uuid,
+        req: EditMessageRequest,
+        user: User,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        match ws_handler.edit_message(user.id, message_id, req.content, None).await {
+            Ok(message) => Ok(warp::reply::json(&message)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_delete_message(
+        message_id: Uuid,
+        user: User,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        match ws_handler.delete_message(user.id, message_id, None).await {
+            Ok(message) => Ok(warp::reply::json(&message)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_receipts(
+        message_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let message = storage.get_message(message_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        let role = storage.get_member_role(message.conversation_id, user.id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        if role.is_none() {
+            return Err(warp::reject::custom(HandlerError::Storage(StorageError::NotFound)));
+        }
+
+        match storage.get_message_receipts(message_id).await {
+            Ok(receipts) => Ok(warp::reply::json(&receipts)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_report_message(
+        message_id: Uuid,
+        req: ReportMessageRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let reason = req.reason.trim();
+        if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+            return Err(warp::reject::custom(HandlerError::InvalidInput(format!(
+                "Reason must be between 1 and {} characters",
+                MAX_REPORT_REASON_LENGTH
+            ))));
+        }
+
+        match storage.create_report(user.id, message_id, reason.to_string()).await {
+            Ok(report) => Ok(warp::reply::json(&report)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_revisions(
+        message_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let message = storage.get_message(message_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        let role = storage.get_member_role(message.conversation_id, user.id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+        if role.is_none() {
+            return Err(warp::reject::custom(HandlerError::Storage(StorageError::NotFound)));
+        }
+
+        match storage.get_message_revisions(message_id).await {
+            Ok(revisions) => Ok(warp::reply::json(&revisions)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_messages(
+        query: MessageQuery,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let messages = storage.get_user_messages(user.id, query.limit, query.offset).await;
+
+        match messages {
+            Ok(msgs) => Ok(warp::reply::json(&msgs)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_conversations(
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.get_user_conversations(user.id).await {
+            Ok(conversations) => Ok(warp::reply::json(&conversations)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_create_conversation(
+        req: CreateConversationRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        if req.kind == ConversationKind::Direct {
+            return Err(warp::reject::custom(HandlerError::InvalidInput(
+                "Direct conversations are created by sending a message".to_string(),
+            )));
+        }
+
+        match storage.create_conversation(user.id, req.kind, req.title, &req.member_ids).await {
+            Ok(conversation) => Ok(warp::reply::json(&conversation)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_conversation_messages(
+        peer_or_id: Uuid,
+        query: HistoryQuery,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let before = match query.before.as_deref() {
+            Some(cursor) => Some(MessageCursor::parse(cursor).ok_or_else(|| {
+                warp::reject::custom(HandlerError::InvalidInput("Invalid cursor".to_string()))
+            })?),
+            None => None,
+        };
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_HISTORY_PAGE_SIZE)
+            .clamp(1, MAX_HISTORY_PAGE_SIZE);
+
+        let conversation = storage.find_conversation_for(user.id, peer_or_id).await
+            .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?;
+
+        let page = match conversation {
+            Some(conversation) => storage
+                .get_conversation_messages(conversation.id, user.id, before.as_ref(), limit)
+                .await
+                .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?,
+            None => MessagePage { messages: Vec::new(), next_cursor: None },
+        };
+
+        Ok(warp::reply::json(&page))
+    }
+
+    async fn handle_search(
+        query: SearchQuery,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        let terms = query.q.trim();
+        if terms.is_empty() {
+            return Err(warp::reject::custom(HandlerError::InvalidInput("Empty search query".to_string())));
+        }
+
+        let conversation_id = match query.peer {
+            Some(peer_or_id) => {
+                match storage.find_conversation_for(user.id, peer_or_id).await
+                    .map_err(|e| warp::reject::custom(HandlerError::Storage(e)))?
+                {
+                    Some(conversation) => Some(conversation.id),
+                    None => return Ok(warp::reply::json(&Vec::<()>::new())),
+                }
+            }
+            None => None,
+        };
+
+        let filters = SearchFilters {
+            sender_id: query.from,
+            conversation_id,
+            before: query.before,
+            after: query.after,
+        };
+        let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE_SIZE).clamp(1, MAX_HISTORY_PAGE_SIZE);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        match storage.search_messages(user.id, terms, &filters, limit, offset).await {
+            Ok(hits) => Ok(warp::reply::json(&hits)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_get_presence(
+        query: PresenceQuery,
+        user: User,
+        ws_handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let ids = query
+            .ids
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .map(|id| id.trim().parse::<Uuid>())
+            .collect::<Result<Vec<Uuid>, _>>()
+            .map_err(|_| warp::reject::custom(HandlerError::InvalidInput("Invalid user id".to_string())))?;
+
+        if ids.len() > MAX_PRESENCE_IDS {
+            return Err(warp::reject::custom(HandlerError::InvalidInput(format!(
+                "At most {} ids per request",
+                MAX_PRESENCE_IDS
+            ))));
+        }
+
+        match ws_handler.presence_snapshot(user.id, &ids).await {
+            Ok(snapshot) => Ok(warp::reply::json(&snapshot)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_update_presence_settings(
+        req: PresenceSettingsRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.set_last_seen_visibility(user.id, req.show_last_seen).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "show_last_seen": req.show_last_seen }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_join_conversation(
+        conversation_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.join_conversation(conversation_id, user.id).await {
+            Ok(conversation) => Ok(warp::reply::json(&conversation)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_leave_conversation(
+        conversation_id: Uuid,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.leave_conversation(conversation_id, user.id).await {
+            Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "left": conversation_id }))),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_add_member(
+        conversation_id: Uuid,
+        req: AddMemberRequest,
+        user: User,
+        storage: Arc<Storage>,
+    ) -> Result<impl Reply, Rejection> {
+        match storage.add_conversation_member(conversation_id, user.id, req.user_id).await {
+            Ok(conversation) => Ok(warp::reply::json(&conversation)),
+            Err(e) => Err(warp::reject::custom(HandlerError::Storage(e))),
+        }
+    }
+
+    async fn handle_ws_upgrade(
+        ws: warp::ws::Ws,
+        query: WebSocketQuery,
+        auth: Arc<Auth>,
+        handler: Arc<WebSocketHandler>,
+    ) -> Result<impl Reply, Rejection> {
+        let (user, session_id) = auth.authenticate(&query.token).await
             .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))?;
 
+        let protocol_version = negotiate_protocol(query.protocol).ok_or_else(|| {
+            warp::reject::custom(HandlerError::InvalidInput("Unsupported protocol version".to_string()))
+        })?;
+
+        let codec = match query.encoding.as_deref() {
+            None => Codec::Json,
+            Some(name) => Codec::parse(name).ok_or_else(|| {
+                warp::reject::custom(HandlerError::InvalidInput("Unsupported encoding".to_string()))
+            })?,
+        };
+
         let user = Arc::new(user);
+        let last_seq = query.last_seq;
         let handler = handler.clone();
 
         Ok(ws.on_upgrade(move |socket| {
             let user = user.clone();
             let handler = handler.clone();
             async move {
-                handler.as_ref().handle_connection(&user, socket).await
+                handler.as_ref().handle_connection(&user, session_id, protocol_version, codec, last_seq, socket).await
             }
         }))
     }
 
     async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
-        let (code, message) = if err.is_not_found() {
-            (404, "Not Found")
+        let retry_after = match err.find::<HandlerError>() {
+            Some(HandlerError::RateLimited { retry_after_secs })
+            | Some(HandlerError::Auth(AuthError::AccountLocked { retry_after_secs })) => Some(*retry_after_secs),
+            _ => None,
+        };
+
+        let (status, code, message) = if err.is_not_found() {
+            (404, "not_found", "Not Found")
         } else if let Some(e) = err.find::<HandlerError>() {
+            let reply = match e {
+                HandlerError::Auth(AuthError::DatabaseError(_))
+                | HandlerError::Auth(AuthError::HashingError(_))
+                | HandlerError::Auth(AuthError::TokenError(_)) => (500, "internal", "Internal Server Error"),
+                HandlerError::Auth(AuthError::MailError(_)) => (502, "mail_failed", "Could not send email"),
+                HandlerError::Auth(AuthError::TokenExpired) => (401, "token_expired", "Token expired"),
+                HandlerError::Auth(AuthError::EmailTaken) => (409, "email_taken", "Email already in use"),
+                HandlerError::Auth(AuthError::InvalidCode) => (401, "invalid_code", "Invalid code"),
+                HandlerError::Auth(AuthError::TwoFactorAlreadyEnabled) => {
+                    (409, "two_factor_enabled", "Two-factor authentication is already enabled")
+                }
+                HandlerError::Auth(AuthError::TwoFactorNotEnabled) => {
+                    (409, "two_factor_disabled", "Two-factor authentication is not enabled")
+                }
+                HandlerError::Auth(AuthError::AccountLocked { .. }) => {
+                    (429, "account_locked", "Too many failed login attempts")
+                }
+                HandlerError::Auth(AuthError::AccountSuspended) => (403, "account_suspended", "Account suspended"),
+                HandlerError::Auth(_) => (401, "unauthorized", "Unauthorized"),
+                HandlerError::Storage(StorageError::NotFound) => (404, "not_found", "Not Found"),
+                HandlerError::Storage(StorageError::PermissionDenied) => (403, "forbidden", "Forbidden"),
+                HandlerError::Storage(StorageError::Conflict) => (409, "conflict", "Conflict"),
+                HandlerError::Storage(_) => (500, "internal", "Internal Server Error"),
+                HandlerError::InvalidInput(_) => (400, "invalid_input", "Bad Request"),
+                HandlerError::NotFound => (404, "not_found", "Not Found"),
+                HandlerError::Forbidden => (403, "forbidden", "Forbidden"),
+                HandlerError::RateLimited { .. } => (429, "rate_limited", "Too Many Requests"),
+            };
+
+            // The details stay in the server log; clients only get the code.
             match e {
-                HandlerError::Auth(_) => (401, "Unauthorized"),
-                HandlerError::Storage(_) => (500, "Internal Server Error"),
-                HandlerError::InvalidInput(_) => (400, "Bad Request"),
+                HandlerError::InvalidInput(reason) => println!("Rejected invalid input: {}", reason),
+                e if reply.0 >= 500 => eprintln!("Request failed ({}): {:?}", reply.1, e),
+                _ => {}
             }
+            reply
         } else {
-            (500, "Internal Server Error")
+            eprintln!("Unhandled rejection: {:?}", err);
+            (500, "internal", "Internal Server Error")
         };
 
-        Ok(warp::reply::with_status(
+        let mut response = warp::reply::with_status(
             warp::reply::json(&serde_json::json!({
-                "error": message
+                "error": message,
+                "code": code,
             })),
-            warp::http::StatusCode::from_u16(code).unwrap(),
-        ))
+            warp::http::StatusCode::from_u16(status).unwrap(),
+        ).into_response();
+
+        if let Some(secs) = retry_after {
+            response.headers_mut().insert(warp::http::header::RETRY_AFTER, secs.into());
+        }
+
+        Ok(response)
+    }
+}
+
+/// Auth reports a missing user as `InvalidCredentials`; for an admin acting
+/// on a user id that means the user does not exist.
+fn admin_target_error(e: AuthError) -> Rejection {
+    match e {
+        AuthError::InvalidCredentials => warp::reject::custom(HandlerError::NotFound),
+        e => warp::reject::custom(HandlerError::Auth(e)),
     }
 }
 
@@ -342,10 +2112,336 @@ fn with_auth(auth: Arc<Auth>) -> impl Filter<Extract = (Arc<Auth>,), Error = std
     warp::any().map(move || auth.clone())
 }
 
+fn with_user(auth: Arc<Auth>) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    with_session(auth).map(|user: User, _session_id: Uuid| user)
+}
+
+fn with_admin(auth: Arc<Auth>) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    with_user(auth).and_then(|user: User| async move {
+        if user.is_admin() {
+            Ok(user)
+        } else {
+            Err(warp::reject::custom(HandlerError::Forbidden))
+        }
+    })
+}
+
+fn with_session(auth: Arc<Auth>) -> impl Filter<Extract = (User, Uuid), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_auth(auth))
+        .and_then(authenticate_bearer)
+        .untuple_one()
+}
+
+async fn authenticate_bearer(header: Option<String>, auth: Arc<Auth>) -> Result<(User, Uuid), Rejection> {
+    let token = header
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| warp::reject::custom(HandlerError::Auth(AuthError::InvalidToken)))?;
+
+    auth.authenticate(token).await
+        .map_err(|e| warp::reject::custom(HandlerError::Auth(e)))
+}
+
+fn is_valid_email(email: &str) -> bool {
+    email.len() <= MAX_EMAIL_LENGTH
+        && !email.contains(char::is_whitespace)
+        && email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.') && !domain.contains('@'))
+}
+
+/// Rejects with 429 once the client's IP runs out of tokens.
+fn rate_limit(
+    limiter: Arc<RateLimiter>,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(trusted_proxies)
+        .and(with_rate_limiter(limiter))
+        .and_then(|ip: Option<IpAddr>, limiter: Arc<RateLimiter>| async move {
+            let key = ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+            check_rate_limit(&limiter, &key)
+        })
+        .untuple_one()
+}
+
+/// The client's address: the peer, unless the peer is a trusted proxy, in
+/// which case it is taken from `X-Forwarded-For`.
+fn client_ip(
+    trusted_proxies: Arc<Vec<IpAddr>>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |addr: Option<SocketAddr>, forwarded_for: Option<String>| {
+            let peer = addr?.ip();
+            if !trusted_proxies.contains(&peer) {
+                return Some(peer);
+            }
+            Some(forwarded_for.as_deref().and_then(|header| forwarded_client(header, &trusted_proxies)).unwrap_or(peer))
+        })
+}
+
+/// Each proxy appends the address it received the request from, so the client
+/// is the rightmost address that is not one of our own proxies. Anything left
+/// of it was written by the client and proves nothing.
+fn forwarded_client(header: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    header
+        .rsplit(',')
+        .map_while(|hop| hop.trim().parse::<IpAddr>().ok())
+        .find(|ip| !trusted_proxies.contains(ip))
+}
+
+fn check_rate_limit(limiter: &RateLimiter, key: &str) -> Result<(), Rejection> {
+    limiter.check(key).map_err(|wait| {
+        warp::reject::custom(HandlerError::RateLimited { retry_after_secs: wait.as_secs() + 1 })
+    })
+}
+
+fn with_rate_limiter(limiter: Arc<RateLimiter>) -> impl Filter<Extract = (Arc<RateLimiter>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
 fn with_storage(storage: Arc<Storage>) -> impl Filter<Extract = (Arc<Storage>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || storage.clone())
 }
 
 fn with_ws_handler(handler: Arc<WebSocketHandler>) -> impl Filter<Extract = (Arc<WebSocketHandler>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || handler.clone())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_messenger::models::MemberRole;
+    use crate::realtime_messenger::websocket::WebSocketEvent;
+    use crate::realtime_messenger::{FileMailer, InMemoryPubSub, WebSocketConfig};
+    use sqlx::postgres::PgPoolOptions;
+
+    /// Handlers on the development database. Like the query macros, these
+    /// tests need `DATABASE_URL` to point at a migrated database.
+    async fn handlers() -> Handlers {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let db_pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
+        let dir = std::env::temp_dir().join("messenger-tests");
+
+        let auth = Auth::new(
+            db_pool.clone(),
+            "test-secret".to_string(),
+            Arc::new(FileMailer::new(dir.join("mail"))),
+            "http://localhost".to_string(),
+        );
+        let storage = Storage::new(db_pool, dir.join("files"));
+        let ws_handler = WebSocketHandler::new(storage.clone(), Arc::new(InMemoryPubSub::new()), WebSocketConfig::default());
+        Handlers::new(auth, storage, ws_handler)
+    }
+
+    async fn register(handlers: &Handlers) -> (User, AuthTokens) {
+        let username = format!("test_{}", Uuid::new_v4().simple());
+        handlers
+            .auth
+            .register_user(username.clone(), format!("{}@example.com", username), "correct horse battery".to_string(), None)
+            .await
+            .unwrap()
+    }
+
+    async fn remove_users(handlers: &Handlers, user_ids: &[Uuid]) {
+        for user_id in user_ids {
+            handlers.storage.delete_account(*user_id, AccountDeletionMode::Cascade).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn forwarded_client_is_the_last_address_before_our_proxies() {
+        let proxies: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+
+        assert_eq!(forwarded_client("203.0.113.7", &proxies), "203.0.113.7".parse().ok());
+        assert_eq!(forwarded_client("1.2.3.4, 203.0.113.7, 10.0.0.2", &proxies), "203.0.113.7".parse().ok());
+        assert_eq!(forwarded_client("10.0.0.1", &proxies), None);
+        assert_eq!(forwarded_client("203.0.113.7, garbage", &proxies), None);
+    }
+
+    #[tokio::test]
+    async fn registration_checks_email_and_password() {
+        let handlers = handlers().await;
+        let register = |email: &str, password: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/auth/register")
+                .json(&serde_json::json!({
+                    "username": format!("test_{}", Uuid::new_v4().simple()),
+                    "email": email,
+                    "password": password,
+                }))
+        };
+
+        let response = register("not-an-email", "correct horse battery").reply(&handlers.routes()).await;
+        assert_eq!(response.status(), 400);
+        let response = register("short@example.com", "short").reply(&handlers.routes()).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn rejections_carry_a_stable_code_and_no_details() {
+        let handlers = handlers().await;
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/auth/register")
+            .json(&serde_json::json!({
+                "username": format!("test_{}", Uuid::new_v4().simple()),
+                "email": "not-an-email",
+                "password": "correct horse battery",
+            }))
+            .reply(&handlers.routes())
+            .await;
+
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": "Bad Request", "code": "invalid_input" }));
+
+        let response = warp::test::request().path("/api/conversations").reply(&handlers.routes()).await;
+        assert_eq!(response.status(), 401);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn messages_sent_over_rest_are_logged_for_every_member() {
+        let handlers = handlers().await;
+        let (sender, tokens) = register(&handlers).await;
+        let (first, _) = register(&handlers).await;
+        let (second, _) = register(&handlers).await;
+        let conversation = handlers
+            .storage
+            .create_conversation(sender.id, ConversationKind::Group, Some("Test".to_string()), &[first.id, second.id])
+            .await
+            .unwrap();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/messages")
+            .header("authorization", format!("Bearer {}", tokens.access_token))
+            .json(&serde_json::json!({ "conversation_id": conversation.id, "content": "hello" }))
+            .reply(&handlers.routes())
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let message: Message = serde_json::from_slice(response.body()).unwrap();
+        let expected = serde_json::to_value(WebSocketEvent::MessageReceived(message)).unwrap();
+        for member_id in [sender.id, first.id, second.id] {
+            let events = handlers.storage.get_user_events_since(member_id, 0, 100).await.unwrap();
+            assert!(
+                events.iter().any(|logged| logged.event == expected),
+                "message not logged for {}",
+                member_id,
+            );
+        }
+
+        remove_users(&handlers, &[sender.id, first.id, second.id]).await;
+    }
+
+    #[tokio::test]
+    async fn messages_are_delivered_once_written_to_the_recipient_socket() {
+        let handlers = handlers().await;
+        let (sender, tokens) = register(&handlers).await;
+        let (recipient, recipient_tokens) = register(&handlers).await;
+        let conversation = handlers
+            .storage
+            .create_conversation(sender.id, ConversationKind::Group, None, &[recipient.id])
+            .await
+            .unwrap();
+
+        let mut socket = warp::test::ws()
+            .path(&format!("/api/ws?token={}", recipient_tokens.access_token))
+            .handshake(handlers.routes())
+            .await
+            .unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/messages")
+            .header("authorization", format!("Bearer {}", tokens.access_token))
+            .json(&serde_json::json!({ "conversation_id": conversation.id, "content": "hello" }))
+            .reply(&handlers.routes())
+            .await;
+        let message: Message = serde_json::from_slice(response.body()).unwrap();
+
+        loop {
+            let frame = socket.recv().await.unwrap();
+            if frame.to_str().is_ok_and(|text| text.contains("MessageReceived")) {
+                break;
+            }
+        }
+        let mut receipts = Vec::new();
+        for _ in 0..50 {
+            receipts = handlers.storage.get_message_receipts(message.id).await.unwrap();
+            if !receipts.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].user_id, recipient.id);
+        assert!(handlers.storage.get_message(message.id).await.unwrap().delivered_at.is_some());
+
+        drop(socket);
+        remove_users(&handlers, &[sender.id, recipient.id]).await;
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_leaves_groups_in_the_same_transaction() {
+        let handlers = handlers().await;
+        let (owner, _) = register(&handlers).await;
+        let (member, _) = register(&handlers).await;
+        let conversation = handlers
+            .storage
+            .create_conversation(owner.id, ConversationKind::Group, None, &[member.id])
+            .await
+            .unwrap();
+
+        // An account that can no longer be anonymized must keep its groups.
+        let db_pool = PgPoolOptions::new().connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        sqlx::query!("UPDATE users SET deleted_at = NOW() WHERE id = $1", owner.id)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        let result = handlers.storage.delete_account(owner.id, AccountDeletionMode::Anonymize).await;
+        assert!(matches!(result, Err(StorageError::NotFound)));
+        let owner_role = handlers.storage.get_member_role(conversation.id, owner.id).await.unwrap();
+        assert_eq!(owner_role, Some(MemberRole::Owner));
+
+        sqlx::query!("UPDATE users SET deleted_at = NULL WHERE id = $1", owner.id)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        let revoked = handlers.storage.delete_account(owner.id, AccountDeletionMode::Cascade).await.unwrap();
+        assert_eq!(revoked.len(), 1);
+        let member_role = handlers.storage.get_member_role(conversation.id, member.id).await.unwrap();
+        assert_eq!(member_role, Some(MemberRole::Owner));
+
+        remove_users(&handlers, &[member.id]).await;
+    }
+
+    #[tokio::test]
+    async fn blocked_users_cannot_be_put_in_a_conversation_together() {
+        let handlers = handlers().await;
+        let (owner, _) = register(&handlers).await;
+        let (member, _) = register(&handlers).await;
+        let (blocked, _) = register(&handlers).await;
+        handlers.storage.block_user(member.id, blocked.id).await.unwrap();
+
+        let result = handlers
+            .storage
+            .create_conversation(owner.id, ConversationKind::Group, None, &[member.id, blocked.id])
+            .await;
+        assert!(matches!(result, Err(StorageError::PermissionDenied)));
+
+        let conversation = handlers
+            .storage
+            .create_conversation(owner.id, ConversationKind::Group, None, &[member.id])
+            .await
+            .unwrap();
+        let result = handlers.storage.add_conversation_member(conversation.id, owner.id, blocked.id).await;
+        assert!(matches!(result, Err(StorageError::PermissionDenied)));
+
+        remove_users(&handlers, &[owner.id, member.id, blocked.id]).await;
+    }
+}
@@ -0,0 +1,120 @@
+use futures::future::BoxFuture;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message as MailMessage, Tokio1Executor};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum MailerError {
+    InvalidAddress(lettre::address::AddressError),
+    Build(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    FileSystem(std::io::Error),
+}
+
+impl std::fmt::Display for MailerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailerError::InvalidAddress(e) => write!(f, "invalid address: {}", e),
+            MailerError::Build(e) => write!(f, "could not build message: {}", e),
+            MailerError::Smtp(e) => write!(f, "SMTP error: {}", e),
+            MailerError::FileSystem(e) => write!(f, "could not write message: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Outgoing mail. Implementations only deliver; composing the message is up
+/// to the caller.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), MailerError>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+/// Delivers through an SMTP relay using STARTTLS.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, MailerError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+            .map_err(MailerError::Smtp)?;
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (config.username, config.password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse().map_err(MailerError::InvalidAddress)?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), MailerError>> {
+        Box::pin(async move {
+            let message = MailMessage::builder()
+                .from(self.from.clone())
+                .to(email.to.parse().map_err(MailerError::InvalidAddress)?)
+                .subject(email.subject)
+                .body(email.body)
+                .map_err(MailerError::Build)?;
+
+            self.transport.send(message).await.map_err(MailerError::Smtp)?;
+            Ok(())
+        })
+    }
+}
+
+/// Writes every email to its own file and logs it, for running locally
+/// without a mail server.
+pub struct FileMailer {
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(directory: PathBuf) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            println!("Warning: Could not create mail directory at {:?}: {}", directory, e);
+        }
+
+        Self { directory }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), MailerError>> {
+        Box::pin(async move {
+            let path = self.directory.join(format!(
+                "{}-{}.eml",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                Uuid::new_v4().simple(),
+            ));
+            let contents = format!("To: {}\nSubject: {}\n\n{}\n", email.to, email.subject, email.body);
+
+            tokio::fs::write(&path, contents).await.map_err(MailerError::FileSystem)?;
+            println!("Mail to {} ({}) written to {:?}", email.to, email.subject, path);
+            Ok(())
+        })
+    }
+}
@@ -3,6 +3,14 @@ pub mod auth;
 pub mod websocket;
 pub mod storage;
 pub mod handlers;
+pub mod pubsub;
+pub mod outbound;
+pub mod codec;
+pub mod presence;
+pub mod typing;
+pub mod mailer;
+pub mod totp;
+pub mod rate_limit;
 pub mod ui;
 
 use sqlx;
@@ -12,8 +20,10 @@ use crate::realtime_messenger::websocket::WebSocketError;
 
 pub use self::auth::Auth;
 pub use self::handlers::Handlers;
+pub use self::mailer::{FileMailer, Mailer, SmtpConfig, SmtpMailer};
+pub use self::pubsub::{InMemoryPubSub, PostgresPubSub, PubSub};
 pub use self::storage::Storage;
-pub use self::websocket::WebSocketHandler;
+pub use self::websocket::{WebSocketConfig, WebSocketHandler};
 
 #[derive(Debug)]
 pub enum MessengerError {
@@ -7,21 +7,108 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
     #[serde(skip_serializing)]
     pub password_hash: String,
     pub created_at: DateTime<Utc>,
-    pub last_seen: DateTime<Utc>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_path: Option<String>,
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// One of `UserRole`, kept as text so `User` maps the row as is.
+    pub role: String,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspension_reason: Option<String>,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        UserRole::parse(&self.role) == Some(UserRole::Admin)
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    User,
+    Admin,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::User => "user",
+            UserRole::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "user" => Some(UserRole::User),
+            "admin" => Some(UserRole::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// What other users get to see of someone: never the email address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicProfile {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_path: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: Uuid,
+    pub conversation_id: Uuid,
     pub sender_id: Uuid,
-    pub receiver_id: Uuid,
+    pub receiver_id: Option<Uuid>,
     pub content: String,
     pub content_type: MessageType,
     pub created_at: DateTime<Utc>,
+    /// When the first recipient got there, derived from the receipts.
+    pub delivered_at: Option<DateTime<Utc>>,
     pub read_at: Option<DateTime<Utc>>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Only filled in for history; live events carry `ReactionChanged`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionCount>,
+}
+
+/// How far one recipient has got with a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageReceipt {
+    pub user_id: Uuid,
+    pub delivered_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+/// How many users reacted to a message with one emoji.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    /// Whether the user loading the history is one of them.
+    pub reacted: bool,
+}
+
+/// Content a message had before an edit or deletion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,4 +117,301 @@ pub enum MessageType {
     File { filename: String, size: usize },
     Voice { duration: u32 },
     Video { duration: u32 },
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConversationKind {
+    Direct,
+    Group,
+    Channel,
+}
+
+impl ConversationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConversationKind::Direct => "direct",
+            ConversationKind::Group => "group",
+            ConversationKind::Channel => "channel",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "direct" => Some(ConversationKind::Direct),
+            "group" => Some(ConversationKind::Group),
+            "channel" => Some(ConversationKind::Channel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl MemberRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberRole::Owner => "owner",
+            MemberRole::Admin => "admin",
+            MemberRole::Member => "member",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "owner" => Some(MemberRole::Owner),
+            "admin" => Some(MemberRole::Admin),
+            "member" => Some(MemberRole::Member),
+            _ => None,
+        }
+    }
+
+    pub fn can_manage_members(&self) -> bool {
+        matches!(self, MemberRole::Owner | MemberRole::Admin)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: Uuid,
+    pub kind: ConversationKind,
+    pub title: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub members: Vec<ConversationMember>,
+}
+
+impl Conversation {
+    pub fn has_member(&self, user_id: Uuid) -> bool {
+        self.members.iter().any(|member| member.user_id == user_id)
+    }
+
+    /// The other participant of a direct conversation, or the user themself
+    /// for a conversation with only one member.
+    pub fn direct_peer(&self, user_id: Uuid) -> Option<Uuid> {
+        if self.kind != ConversationKind::Direct {
+            return None;
+        }
+
+        self.members
+            .iter()
+            .map(|member| member.user_id)
+            .find(|id| *id != user_id)
+            .or(Some(user_id))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationMember {
+    pub user_id: Uuid,
+    pub role: MemberRole,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Availability as seen by other users. `Invisible` is only ever chosen by a
+/// user for themself and shows up as `Offline` to everyone else.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    DoNotDisturb,
+    Invisible,
+    Offline,
+}
+
+impl PresenceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceStatus::Online => "online",
+            PresenceStatus::Away => "away",
+            PresenceStatus::DoNotDisturb => "do_not_disturb",
+            PresenceStatus::Invisible => "invisible",
+            PresenceStatus::Offline => "offline",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "online" => Some(PresenceStatus::Online),
+            "away" => Some(PresenceStatus::Away),
+            "do_not_disturb" => Some(PresenceStatus::DoNotDisturb),
+            "invisible" => Some(PresenceStatus::Invisible),
+            "offline" => Some(PresenceStatus::Offline),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub user_id: Uuid,
+    pub status: PresenceStatus,
+    /// Only present for offline users who share their last-seen time.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContactRequestStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+impl ContactRequestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContactRequestStatus::Pending => "pending",
+            ContactRequestStatus::Accepted => "accepted",
+            ContactRequestStatus::Declined => "declined",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(ContactRequestStatus::Pending),
+            "accepted" => Some(ContactRequestStatus::Accepted),
+            "declined" => Some(ContactRequestStatus::Declined),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactRequest {
+    pub id: Uuid,
+    pub requester_id: Uuid,
+    pub addressee_id: Uuid,
+    pub status: ContactRequestStatus,
+    pub created_at: DateTime<Utc>,
+    pub responded_at: Option<DateTime<Utc>>,
+}
+
+/// Another user as listed among someone's contacts or blocked users. Never
+/// carries the email address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub user_id: Uuid,
+    pub username: String,
+    pub since: DateTime<Utc>,
+}
+
+/// What happens to a deleted account's messages and uploaded files.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountDeletionMode {
+    /// Keeps messages and files, but strips the account of everything that
+    /// identifies its owner.
+    Anonymize,
+    /// Removes the account together with its messages, direct conversations
+    /// and files.
+    Cascade,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Dismissed,
+    Actioned,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Dismissed => "dismissed",
+            ReportStatus::Actioned => "actioned",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "open" => Some(ReportStatus::Open),
+            "dismissed" => Some(ReportStatus::Dismissed),
+            "actioned" => Some(ReportStatus::Actioned),
+            _ => None,
+        }
+    }
+}
+
+/// A user's complaint about a message, with the content as it was when
+/// reported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub conversation_id: Uuid,
+    pub reporter_id: Uuid,
+    pub sender_id: Uuid,
+    pub message_content: String,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub resolved_by: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationActionKind {
+    DismissReport,
+    DeleteMessage,
+    SuspendUser,
+    UnsuspendUser,
+    ForcePasswordReset,
+}
+
+impl ModerationActionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationActionKind::DismissReport => "dismiss_report",
+            ModerationActionKind::DeleteMessage => "delete_message",
+            ModerationActionKind::SuspendUser => "suspend_user",
+            ModerationActionKind::UnsuspendUser => "unsuspend_user",
+            ModerationActionKind::ForcePasswordReset => "force_password_reset",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "dismiss_report" => Some(ModerationActionKind::DismissReport),
+            "delete_message" => Some(ModerationActionKind::DeleteMessage),
+            "suspend_user" => Some(ModerationActionKind::SuspendUser),
+            "unsuspend_user" => Some(ModerationActionKind::UnsuspendUser),
+            "force_password_reset" => Some(ModerationActionKind::ForcePasswordReset),
+            _ => None,
+        }
+    }
+}
+
+/// An entry in the moderation audit trail.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationAction {
+    pub id: Uuid,
+    pub admin_id: Option<Uuid>,
+    pub action: ModerationActionKind,
+    pub target_user_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub report_id: Option<Uuid>,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
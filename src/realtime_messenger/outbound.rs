@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use uuid::Uuid;
+use warp::ws::Message as WsMessage;
+
+/// Whether an outbound frame may be thrown away under pressure. Presence,
+/// typing and pings are superseded by the next one; everything else is
+/// essential.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Priority {
+    Droppable,
+    Essential,
+}
+
+/// A queued frame, and the chat message it hands to the client if any, so the
+/// writer can record delivery once the frame is actually written.
+pub struct Frame {
+    pub message: WsMessage,
+    pub delivers: Option<Uuid>,
+}
+
+impl From<WsMessage> for Frame {
+    fn from(message: WsMessage) -> Self {
+        Self { message, delivers: None }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PushResult {
+    Queued,
+    /// The frame was queued after evicting an older droppable one, or was
+    /// itself dropped.
+    Overflowed,
+    /// The consumer is too slow to keep: the queue has been closed.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub high_water_mark: usize,
+    pub overflows: u32,
+    pub dropped: u64,
+}
+
+struct QueueState {
+    items: VecDeque<(Priority, Frame)>,
+    closed: bool,
+    overflows: u32,
+    dropped: u64,
+    high_water_mark: usize,
+}
+
+/// Bounded per-connection queue between event producers and the socket
+/// writer. When full, the oldest droppable frame makes room. If there is
+/// nothing to drop, or the connection has overflowed `max_overflows` times,
+/// the queue closes so the client reconnects and catches up through replay.
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    max_overflows: u32,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, max_overflows: u32) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+                overflows: 0,
+                dropped: 0,
+                high_water_mark: 0,
+            }),
+            notify: Notify::new(),
+            capacity,
+            max_overflows,
+        }
+    }
+
+    pub fn push(&self, priority: Priority, frame: impl Into<Frame>) -> PushResult {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return PushResult::Disconnect;
+        }
+
+        let mut result = PushResult::Queued;
+        if state.items.len() >= self.capacity {
+            state.overflows += 1;
+            if state.overflows > self.max_overflows {
+                Self::close_locked(&mut state);
+                drop(state);
+                self.notify.notify_one();
+                return PushResult::Disconnect;
+            }
+
+            let oldest_droppable = state
+                .items
+                .iter()
+                .position(|(queued, _)| *queued == Priority::Droppable);
+
+            match (oldest_droppable, priority) {
+                (Some(index), _) => {
+                    state.items.remove(index);
+                    state.dropped += 1;
+                }
+                (None, Priority::Droppable) => {
+                    state.dropped += 1;
+                    return PushResult::Overflowed;
+                }
+                (None, Priority::Essential) => {
+                    Self::close_locked(&mut state);
+                    drop(state);
+                    self.notify.notify_one();
+                    return PushResult::Disconnect;
+                }
+            }
+            result = PushResult::Overflowed;
+        }
+
+        state.items.push_back((priority, frame.into()));
+        state.high_water_mark = state.high_water_mark.max(state.items.len());
+        drop(state);
+
+        self.notify.notify_one();
+        result
+    }
+
+    /// Waits for the next frame. Returns `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<Frame> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some((_, frame)) = state.items.pop_front() {
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        Self::close_locked(&mut self.state.lock().unwrap());
+        self.notify.notify_one();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            depth: state.items.len(),
+            high_water_mark: state.high_water_mark,
+            overflows: state.overflows,
+            dropped: state.dropped,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn close_locked(state: &mut QueueState) {
+        state.closed = true;
+        state.dropped += state.items.len() as u64;
+        state.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(queue: &OutboundQueue) -> Option<String> {
+        let state = queue.state.lock().unwrap();
+        state.items.front().map(|(_, frame)| frame.message.to_str().unwrap().to_string())
+    }
+
+    async fn pop_text(queue: &OutboundQueue) -> Option<String> {
+        queue.pop().await.map(|frame| frame.message.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn frames_come_out_in_order() {
+        let queue = OutboundQueue::new(4, 1);
+        assert_eq!(queue.push(Priority::Essential, WsMessage::text("a")), PushResult::Queued);
+        assert_eq!(queue.push(Priority::Droppable, WsMessage::text("b")), PushResult::Queued);
+
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("a"));
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("b"));
+        assert_eq!(queue.stats().high_water_mark, 2);
+    }
+
+    #[tokio::test]
+    async fn full_queue_evicts_the_oldest_droppable_frame() {
+        let queue = OutboundQueue::new(3, 5);
+        queue.push(Priority::Essential, WsMessage::text("a"));
+        queue.push(Priority::Droppable, WsMessage::text("b"));
+        queue.push(Priority::Droppable, WsMessage::text("c"));
+
+        assert_eq!(queue.push(Priority::Essential, WsMessage::text("d")), PushResult::Overflowed);
+
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("a"));
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("c"));
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("d"));
+        let stats = queue.stats();
+        assert_eq!((stats.overflows, stats.dropped), (1, 1));
+    }
+
+    #[test]
+    fn droppable_frame_is_dropped_when_nothing_can_be_evicted() {
+        let queue = OutboundQueue::new(1, 5);
+        queue.push(Priority::Essential, WsMessage::text("a"));
+
+        assert_eq!(queue.push(Priority::Droppable, WsMessage::text("b")), PushResult::Overflowed);
+        assert_eq!(text(&queue).as_deref(), Some("a"));
+        assert_eq!(queue.stats().depth, 1);
+    }
+
+    #[tokio::test]
+    async fn essential_frame_that_does_not_fit_closes_the_queue() {
+        let queue = OutboundQueue::new(1, 5);
+        queue.push(Priority::Essential, WsMessage::text("a"));
+
+        assert_eq!(queue.push(Priority::Essential, WsMessage::text("b")), PushResult::Disconnect);
+        assert_eq!(pop_text(&queue).await, None);
+        assert_eq!(queue.push(Priority::Essential, WsMessage::text("c")), PushResult::Disconnect);
+    }
+
+    #[test]
+    fn too_many_overflows_close_the_queue() {
+        let queue = OutboundQueue::new(1, 1);
+        queue.push(Priority::Droppable, WsMessage::text("a"));
+
+        assert_eq!(queue.push(Priority::Droppable, WsMessage::text("b")), PushResult::Overflowed);
+        assert_eq!(queue.push(Priority::Droppable, WsMessage::text("c")), PushResult::Disconnect);
+        assert_eq!(queue.stats().depth, 0);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_a_waiting_reader() {
+        let queue = std::sync::Arc::new(OutboundQueue::new(1, 1));
+        let reader = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await.is_none() }
+        });
+
+        tokio::task::yield_now().await;
+        queue.close();
+        assert!(reader.await.unwrap());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::realtime_messenger::models::PresenceStatus;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+struct LocalPresence {
+    chosen: PresenceStatus,
+    last_active: Instant,
+    idle: bool,
+}
+
+/// Presence of users as known to this node: users connected here are tracked
+/// in full, users connected elsewhere only by the last status each other node
+/// announced for them.
+pub struct PresenceTracker {
+    local: RwLock<HashMap<Uuid, LocalPresence>>,
+    /// Per user, the status announced by each node holding them.
+    remote: RwLock<HashMap<Uuid, HashMap<Uuid, PresenceStatus>>>,
+    idle_timeout: Duration,
+}
+
+impl PresenceTracker {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            local: RwLock::new(HashMap::new()),
+            remote: RwLock::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    /// Starts tracking a user who just got their first connection on this
+    /// node and returns the status others should now see.
+    pub async fn connect(&self, user_id: Uuid, chosen: PresenceStatus) -> PresenceStatus {
+        self.local.write().await.insert(user_id, LocalPresence {
+            chosen,
+            last_active: Instant::now(),
+            idle: false,
+        });
+        effective(chosen, false)
+    }
+
+    /// Stops tracking a user whose last connection on this node closed and
+    /// returns the status they had chosen.
+    pub async fn disconnect(&self, user_id: Uuid) -> Option<PresenceStatus> {
+        self.local.write().await.remove(&user_id).map(|presence| presence.chosen)
+    }
+
+    pub async fn chosen(&self, user_id: Uuid) -> Option<PresenceStatus> {
+        self.local.read().await.get(&user_id).map(|presence| presence.chosen)
+    }
+
+    /// Records a status picked by the user. Returns the new visible status if
+    /// it changed.
+    pub async fn set_chosen(&self, user_id: Uuid, chosen: PresenceStatus) -> Option<PresenceStatus> {
+        let mut local = self.local.write().await;
+        let presence = local.get_mut(&user_id)?;
+        let before = effective(presence.chosen, presence.idle);
+        presence.chosen = chosen;
+        presence.idle = false;
+        presence.last_active = Instant::now();
+        changed(before, effective(chosen, false))
+    }
+
+    /// Notes activity from the user. Returns the new visible status if this
+    /// brought them back from being away.
+    pub async fn touch(&self, user_id: Uuid) -> Option<PresenceStatus> {
+        let mut local = self.local.write().await;
+        let presence = local.get_mut(&user_id)?;
+        presence.last_active = Instant::now();
+        if !presence.idle {
+            return None;
+        }
+        presence.idle = false;
+        changed(effective(presence.chosen, true), effective(presence.chosen, false))
+    }
+
+    /// Marks the user idle once they have been inactive for the idle timeout.
+    /// Returns the new visible status if that changed it.
+    pub async fn check_idle(&self, user_id: Uuid) -> Option<PresenceStatus> {
+        let mut local = self.local.write().await;
+        let presence = local.get_mut(&user_id)?;
+        if presence.idle || presence.last_active.elapsed() < self.idle_timeout {
+            return None;
+        }
+        presence.idle = true;
+        changed(effective(presence.chosen, false), effective(presence.chosen, true))
+    }
+
+    /// Records the status `node_id` announced for a user. Offline means the
+    /// user has no connection left on that node.
+    pub async fn record_remote(&self, user_id: Uuid, node_id: Uuid, status: PresenceStatus) {
+        let mut remote = self.remote.write().await;
+        if status == PresenceStatus::Offline {
+            if let Some(nodes) = remote.get_mut(&user_id) {
+                nodes.remove(&node_id);
+                if nodes.is_empty() {
+                    remote.remove(&user_id);
+                }
+            }
+        } else {
+            remote.entry(user_id).or_default().insert(node_id, status);
+        }
+    }
+
+    /// The visible status of a user connected to this node, if they are.
+    pub async fn local_status(&self, user_id: Uuid) -> Option<PresenceStatus> {
+        self.local
+            .read()
+            .await
+            .get(&user_id)
+            .map(|presence| effective(presence.chosen, presence.idle))
+    }
+
+    /// The status other users see for `user_id`: this node's view if the user
+    /// is connected here, otherwise the most available status any other node
+    /// announced.
+    pub async fn status_of(&self, user_id: Uuid) -> PresenceStatus {
+        if let Some(status) = self.local_status(user_id).await {
+            return status;
+        }
+        self.remote
+            .read()
+            .await
+            .get(&user_id)
+            .and_then(|nodes| nodes.values().copied().max_by_key(|status| availability(*status)))
+            .unwrap_or(PresenceStatus::Offline)
+    }
+}
+
+/// Ranks statuses announced by different nodes for the same user. An explicit
+/// do-not-disturb wins over a device that is merely online.
+fn availability(status: PresenceStatus) -> u8 {
+    match status {
+        PresenceStatus::DoNotDisturb => 3,
+        PresenceStatus::Online => 2,
+        PresenceStatus::Away => 1,
+        PresenceStatus::Invisible | PresenceStatus::Offline => 0,
+    }
+}
+
+fn effective(chosen: PresenceStatus, idle: bool) -> PresenceStatus {
+    match chosen {
+        PresenceStatus::Invisible => PresenceStatus::Offline,
+        PresenceStatus::Online if idle => PresenceStatus::Away,
+        status => status,
+    }
+}
+
+fn changed(before: PresenceStatus, after: PresenceStatus) -> Option<PresenceStatus> {
+    (before != after).then_some(after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn invisible_users_look_offline() {
+        let tracker = PresenceTracker::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+
+        assert_eq!(tracker.connect(user_id, PresenceStatus::Invisible).await, PresenceStatus::Offline);
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Offline);
+        assert_eq!(tracker.chosen(user_id).await, Some(PresenceStatus::Invisible));
+    }
+
+    #[tokio::test]
+    async fn idle_users_go_away_until_active_again() {
+        let tracker = PresenceTracker::new(Duration::ZERO);
+        let user_id = Uuid::new_v4();
+        tracker.connect(user_id, PresenceStatus::Online).await;
+
+        assert_eq!(tracker.check_idle(user_id).await, Some(PresenceStatus::Away));
+        assert_eq!(tracker.check_idle(user_id).await, None);
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Away);
+        assert_eq!(tracker.touch(user_id).await, Some(PresenceStatus::Online));
+        assert_eq!(tracker.touch(user_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn idling_does_not_change_a_chosen_status() {
+        let tracker = PresenceTracker::new(Duration::ZERO);
+        let user_id = Uuid::new_v4();
+        tracker.connect(user_id, PresenceStatus::DoNotDisturb).await;
+
+        assert_eq!(tracker.check_idle(user_id).await, None);
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::DoNotDisturb);
+    }
+
+    #[tokio::test]
+    async fn set_chosen_reports_only_visible_changes() {
+        let tracker = PresenceTracker::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+        tracker.connect(user_id, PresenceStatus::Online).await;
+
+        assert_eq!(tracker.set_chosen(user_id, PresenceStatus::DoNotDisturb).await, Some(PresenceStatus::DoNotDisturb));
+        assert_eq!(tracker.set_chosen(user_id, PresenceStatus::DoNotDisturb).await, None);
+        assert_eq!(tracker.set_chosen(Uuid::new_v4(), PresenceStatus::Online).await, None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_returns_the_chosen_status() {
+        let tracker = PresenceTracker::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+        tracker.connect(user_id, PresenceStatus::DoNotDisturb).await;
+
+        assert_eq!(tracker.disconnect(user_id).await, Some(PresenceStatus::DoNotDisturb));
+        assert_eq!(tracker.disconnect(user_id).await, None);
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Offline);
+    }
+
+    #[tokio::test]
+    async fn remote_users_stay_present_while_any_node_holds_them() {
+        let tracker = PresenceTracker::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        tracker.record_remote(user_id, first, PresenceStatus::Online).await;
+        tracker.record_remote(user_id, second, PresenceStatus::Away).await;
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Online);
+
+        tracker.record_remote(user_id, first, PresenceStatus::Offline).await;
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Away);
+
+        tracker.record_remote(user_id, second, PresenceStatus::Offline).await;
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Offline);
+    }
+
+    #[tokio::test]
+    async fn local_connections_win_over_remote_announcements() {
+        let tracker = PresenceTracker::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+        tracker.record_remote(user_id, Uuid::new_v4(), PresenceStatus::Away).await;
+        tracker.connect(user_id, PresenceStatus::Online).await;
+
+        assert_eq!(tracker.local_status(user_id).await, Some(PresenceStatus::Online));
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Online);
+
+        tracker.disconnect(user_id).await;
+        assert_eq!(tracker.local_status(user_id).await, None);
+        assert_eq!(tracker.status_of(user_id).await, PresenceStatus::Away);
+    }
+}
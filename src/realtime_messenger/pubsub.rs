@@ -0,0 +1,270 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use crate::realtime_messenger::models::PresenceStatus;
+use crate::realtime_messenger::outbound::Priority;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+const NOTIFY_CHANNEL: &str = "messenger_events";
+
+/// Postgres rejects NOTIFY payloads of 8000 bytes or more.
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+/// How long an envelope published by reference stays readable.
+const STORED_ENVELOPE_TTL_SECS: f64 = 300.0;
+
+#[derive(Debug)]
+pub enum PubSubError {
+    Database(sqlx::Error),
+    Serialization(serde_json::Error),
+}
+
+impl std::fmt::Display for PubSubError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PubSubError::Database(e) => write!(f, "database error: {}", e),
+            PubSubError::Serialization(e) => write!(f, "could not serialize envelope: {}", e),
+        }
+    }
+}
+
+/// What a node asks every other node to do with its local connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Delivery {
+    User {
+        user_id: Uuid,
+        skip_connection: Option<Uuid>,
+        priority: Priority,
+        payload: Payload,
+        /// The chat message a `MessageReceived` payload hands over.
+        #[serde(default)]
+        delivers: Option<Uuid>,
+    },
+    /// `status` is the user's status on the publishing node alone, Offline
+    /// once their last connection there closed; `event_json` is what the
+    /// audience should see, which accounts for other nodes.
+    Presence { user_id: Uuid, status: PresenceStatus, event_json: String },
+    DisconnectSessions { session_ids: Vec<Uuid> },
+}
+
+/// Small events travel inline. Durable events are already in the recipient's
+/// event log, so only their sequence number is published and receiving nodes
+/// load the event themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Payload {
+    Inline(String),
+    Logged { seq: i64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub origin: Uuid,
+    pub delivery: Delivery,
+}
+
+/// Fan-out between server instances. Every published envelope reaches every
+/// subscriber, including the publishing node, which is expected to skip
+/// envelopes carrying its own `origin`.
+pub trait PubSub: Send + Sync {
+    fn publish(&self, envelope: Envelope) -> BoxFuture<'_, Result<(), PubSubError>>;
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<Envelope>;
+}
+
+/// Single-process backend: enough for one server instance and for wiring
+/// several handlers together in tests.
+#[derive(Default)]
+pub struct InMemoryPubSub {
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Envelope>>>,
+}
+
+impl InMemoryPubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PubSub for InMemoryPubSub {
+    fn publish(&self, envelope: Envelope) -> BoxFuture<'_, Result<(), PubSubError>> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(envelope.clone()).is_ok());
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<Envelope> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// What travels in a NOTIFY payload: the envelope itself, or for envelopes over
+/// the payload limit the id of the `pubsub_envelopes` row holding it.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Inline(Envelope),
+    Stored { envelope_id: i64 },
+}
+
+/// Backend built on Postgres `LISTEN/NOTIFY`, so instances sharing a database
+/// see each other's deliveries without extra infrastructure.
+pub struct PostgresPubSub {
+    db_pool: PgPool,
+}
+
+impl PostgresPubSub {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    async fn listen(db_pool: PgPool, tx: mpsc::UnboundedSender<Envelope>) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(&db_pool).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+
+        loop {
+            let notification = listener.recv().await?;
+            let envelope = match serde_json::from_str::<Notification>(notification.payload()) {
+                Ok(Notification::Inline(envelope)) => envelope,
+                Ok(Notification::Stored { envelope_id }) => match Self::load(&db_pool, envelope_id).await {
+                    Ok(Some(envelope)) => envelope,
+                    Ok(None) => {
+                        eprintln!("Stored envelope {} is gone, skipping it", envelope_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to load stored envelope {}: {}", envelope_id, e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Ignoring malformed notification: {}", e);
+                    continue;
+                }
+            };
+            if tx.send(envelope).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn load(db_pool: &PgPool, envelope_id: i64) -> Result<Option<Envelope>, PubSubError> {
+        let envelope = sqlx::query_scalar!("SELECT envelope FROM pubsub_envelopes WHERE id = $1", envelope_id)
+            .fetch_optional(db_pool)
+            .await
+            .map_err(PubSubError::Database)?;
+
+        envelope
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(PubSubError::Serialization)
+    }
+
+    /// Keeps an envelope too large to notify, pruning the expired ones.
+    async fn store(&self, envelope: &Envelope) -> Result<i64, PubSubError> {
+        let envelope = serde_json::to_value(envelope).map_err(PubSubError::Serialization)?;
+        sqlx::query_scalar!(
+            r#"
+            WITH expired AS (
+                DELETE FROM pubsub_envelopes WHERE created_at < NOW() - make_interval(secs => $2)
+            )
+            INSERT INTO pubsub_envelopes (envelope) VALUES ($1)
+            RETURNING id
+            "#,
+            envelope,
+            STORED_ENVELOPE_TTL_SECS,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(PubSubError::Database)
+    }
+}
+
+impl PubSub for PostgresPubSub {
+    fn publish(&self, envelope: Envelope) -> BoxFuture<'_, Result<(), PubSubError>> {
+        Box::pin(async move {
+            // Untagged, so an inline notification is the envelope as is.
+            let mut payload = serde_json::to_string(&envelope).map_err(PubSubError::Serialization)?;
+            if payload.len() > MAX_NOTIFY_PAYLOAD {
+                let envelope_id = self.store(&envelope).await?;
+                payload = serde_json::to_string(&Notification::Stored { envelope_id })
+                    .map_err(PubSubError::Serialization)?;
+            }
+
+            sqlx::query!("SELECT pg_notify($1, $2)", NOTIFY_CHANNEL, payload)
+                .execute(&self.db_pool)
+                .await
+                .map_err(PubSubError::Database)?;
+
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<Envelope> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let db_pool = self.db_pool.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match Self::listen(db_pool.clone(), tx.clone()).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        eprintln!("Postgres listener failed, reconnecting: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn envelopes_over_the_notify_limit_are_sent_by_reference() {
+        dotenv::dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pubsub = PostgresPubSub::new(PgPool::connect(&database_url).await.unwrap());
+        let mut received = pubsub.subscribe();
+
+        let user_id = Uuid::new_v4();
+        let event_json = "x".repeat(MAX_NOTIFY_PAYLOAD * 2);
+        let envelope = Envelope {
+            origin: Uuid::new_v4(),
+            delivery: Delivery::User {
+                user_id,
+                skip_connection: None,
+                priority: Priority::Essential,
+                payload: Payload::Inline(event_json.clone()),
+                delivers: None,
+            },
+        };
+
+        // The listener connects in the background, so publish until it hears.
+        let mut delivery = None;
+        for _ in 0..25 {
+            pubsub.publish(envelope.clone()).await.unwrap();
+            if let Ok(Some(envelope)) = tokio::time::timeout(Duration::from_millis(200), received.recv()).await {
+                delivery = Some(envelope.delivery);
+                break;
+            }
+        }
+        match delivery.expect("envelope never arrived") {
+            Delivery::User { user_id: received_user, payload: Payload::Inline(received_json), .. } => {
+                assert_eq!(received_user, user_id);
+                assert_eq!(received_json, event_json);
+            }
+            other => panic!("unexpected delivery {:?}", other),
+        }
+    }
+}
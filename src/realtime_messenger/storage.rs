@@ -1,6 +1,12 @@
 use chrono::DateTime;
 use chrono::Utc;
-use super::models::{Message, User};
+use super::models::{
+    AccountDeletionMode, Contact, ContactRequest, ContactRequestStatus, Conversation, ConversationKind,
+    ConversationMember, MemberRole, Message, MessageReceipt, MessageRevision, MessageType, ModerationAction,
+    ModerationActionKind, PresenceStatus, PublicProfile, ReactionCount, Report, ReportStatus, User, UserRole,
+};
+use serde::Serialize;
+use std::collections::HashMap;
 use sqlx::PgPool;
 use uuid::Uuid;
 use std::path::PathBuf;
@@ -15,17 +21,265 @@ pub enum StorageError {
     Database(sqlx::Error),
     FileSystem(std::io::Error),
     NotFound,
+    PermissionDenied,
+    Conflict,
 }
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct DbMessage {
     pub id: Uuid,
+    pub conversation_id: Uuid,
     pub sender_id: Uuid,
-    pub receiver_id: Uuid,
+    pub receiver_id: Option<Uuid>,
     pub content: String,
     pub content_type: serde_json::Value,
     pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
     pub read_at: Option<DateTime<Utc>>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+impl DbMessage {
+    pub fn into_message(self) -> Option<Message> {
+        let content_type = serde_json::from_value(self.content_type).ok()?;
+        Some(Message {
+            id: self.id,
+            conversation_id: self.conversation_id,
+            sender_id: self.sender_id,
+            receiver_id: self.receiver_id,
+            content: self.content,
+            content_type,
+            created_at: self.created_at,
+            delivered_at: self.delivered_at,
+            read_at: self.read_at,
+            edited_at: self.edited_at,
+            deleted_at: self.deleted_at,
+            reactions: Vec::new(),
+        })
+    }
+}
+
+/// Position in a conversation's history, handed to clients as an opaque
+/// `<created_at micros>_<id>` string.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl MessageCursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let (micros, id) = value.split_once('_')?;
+        Some(Self {
+            created_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+    pub sender_id: Option<Uuid>,
+    pub conversation_id: Option<Uuid>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub message: Message,
+    pub rank: f32,
+    pub snippet: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DbSearchHit {
+    id: Uuid,
+    conversation_id: Uuid,
+    sender_id: Uuid,
+    receiver_id: Option<Uuid>,
+    content: String,
+    content_type: serde_json::Value,
+    created_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    read_at: Option<DateTime<Utc>>,
+    edited_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    rank: f32,
+    snippet: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ReceiptUpdate {
+    pub message_id: Uuid,
+    pub sender_id: Uuid,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbReactionCount {
+    pub message_id: Uuid,
+    pub emoji: String,
+    pub count: i64,
+    pub reacted: bool,
+}
+
+/// Result of adding or removing a reaction. `changed` is false when the
+/// reaction was already there (or already gone), so nothing needs sending.
+#[derive(Debug, Clone, Copy)]
+pub struct ReactionUpdate {
+    pub conversation_id: Uuid,
+    pub count: i64,
+    pub changed: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbUserEvent {
+    pub seq: i64,
+    pub event: serde_json::Value,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbConversation {
+    pub id: Uuid,
+    pub kind: String,
+    pub title: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbConversationMember {
+    pub conversation_id: Uuid,
+    pub user_id: Uuid,
+    pub role: String,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbContactRequest {
+    pub id: Uuid,
+    pub requester_id: Uuid,
+    pub addressee_id: Uuid,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub responded_at: Option<DateTime<Utc>>,
+}
+
+impl DbContactRequest {
+    pub fn into_contact_request(self) -> Option<ContactRequest> {
+        Some(ContactRequest {
+            id: self.id,
+            requester_id: self.requester_id,
+            addressee_id: self.addressee_id,
+            status: ContactRequestStatus::parse(&self.status)?,
+            created_at: self.created_at,
+            responded_at: self.responded_at,
+        })
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbReport {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub conversation_id: Uuid,
+    pub reporter_id: Uuid,
+    pub sender_id: Uuid,
+    pub message_content: String,
+    pub reason: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub resolved_by: Option<Uuid>,
+}
+
+impl DbReport {
+    pub fn into_report(self) -> Option<Report> {
+        Some(Report {
+            id: self.id,
+            message_id: self.message_id,
+            conversation_id: self.conversation_id,
+            reporter_id: self.reporter_id,
+            sender_id: self.sender_id,
+            message_content: self.message_content,
+            reason: self.reason,
+            status: ReportStatus::parse(&self.status)?,
+            created_at: self.created_at,
+            resolved_at: self.resolved_at,
+            resolved_by: self.resolved_by,
+        })
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbModerationAction {
+    pub id: Uuid,
+    pub admin_id: Option<Uuid>,
+    pub action: String,
+    pub target_user_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub report_id: Option<Uuid>,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl DbModerationAction {
+    pub fn into_moderation_action(self) -> Option<ModerationAction> {
+        Some(ModerationAction {
+            id: self.id,
+            admin_id: self.admin_id,
+            action: ModerationActionKind::parse(&self.action)?,
+            target_user_id: self.target_user_id,
+            message_id: self.message_id,
+            report_id: self.report_id,
+            note: self.note,
+            created_at: self.created_at,
+        })
+    }
+}
+
+/// Who an audit trail entry is about, besides the admin who acted.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModerationTarget {
+    pub user_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub report_id: Option<Uuid>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct LastSeenRecord {
+    pub user_id: Uuid,
+    pub last_seen: DateTime<Utc>,
+    pub show_last_seen: bool,
+}
+
+/// Database-wide counters for the admin dashboard.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ServerStats {
+    pub users: i64,
+    pub suspended_users: i64,
+    pub admins: i64,
+    pub messages: i64,
+    pub messages_last_24h: i64,
+    pub conversations: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StorageUsage {
+    pub files: u64,
+    pub bytes: u64,
 }
 
 impl Storage {
@@ -46,10 +300,11 @@ impl Storage {
         sqlx::query!(
             r#"
             INSERT INTO messages
-            (id, sender_id, receiver_id, content, content_type, created_at)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            (id, conversation_id, sender_id, receiver_id, content, content_type, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             "#,
             message.id,
+            message.conversation_id,
             message.sender_id,
             message.receiver_id,
             message.content,
@@ -63,24 +318,67 @@ impl Storage {
         Ok(())
     }
 
+    /// Saves a message whose id was generated by the client, so resending it
+    /// after a lost acknowledgement does not store it twice. Returns the stored
+    /// message and whether this call created it. An id already taken by another
+    /// sender is a conflict.
+    pub async fn save_message_idempotent(&self, message: &Message) -> Result<(Message, bool), StorageError> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO messages
+            (id, conversation_id, sender_id, receiver_id, content, content_type, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (id) DO NOTHING
+            "#,
+            message.id,
+            message.conversation_id,
+            message.sender_id,
+            message.receiver_id,
+            message.content,
+            serde_json::to_value(&message.content_type).unwrap(),
+            message.created_at,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        if result.rows_affected() > 0 {
+            return Ok((message.clone(), true));
+        }
+
+        let existing = self.get_message(message.id).await?;
+        if existing.sender_id != message.sender_id {
+            return Err(StorageError::Conflict);
+        }
+
+        Ok((existing, false))
+    }
+
     pub async fn get_user_messages(
         &self,
         user_id: Uuid,
         limit: i64,
         offset: i64,
     ) -> Result<Vec<Message>, StorageError> {
-        let db_messages = sqlx::query!(
+        let db_messages = sqlx::query_as!(
+        DbMessage,
         r#"
         SELECT
             id as "id!",
+            conversation_id as "conversation_id!",
             sender_id as "sender_id!",
-            receiver_id as "receiver_id!",
+            receiver_id,
             content,
             content_type as "content_type!: serde_json::Value",
             created_at as "created_at!",
-            read_at
+            (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+            (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+            edited_at,
+            deleted_at
         FROM messages
-        WHERE sender_id = $1 OR receiver_id = $1
+        WHERE conversation_id IN (
+            SELECT conversation_id FROM conversation_members WHERE user_id = $1
+        )
         ORDER BY created_at DESC
         LIMIT $2 OFFSET $3
         "#,
@@ -92,129 +390,2119 @@ impl Storage {
             .await
             .map_err(StorageError::Database)?;
 
-        let messages = db_messages
+        let mut messages: Vec<Message> = db_messages
             .into_iter()
-            .filter_map(|row| {
-                let content_type = serde_json::from_value(row.content_type).ok()?;
-                Some(Message {
-                    id: row.id,
-                    sender_id: row.sender_id,
-                    receiver_id: row.receiver_id,
-                    content: row.content,
-                    content_type,
-                    created_at: row.created_at,
-                    read_at: row.read_at,
-                })
-            })
+            .filter_map(DbMessage::into_message)
             .collect();
+        self.attach_reactions(user_id, &mut messages).await?;
 
         Ok(messages)
     }
 
-    pub async fn get_users(&self) -> Result<Vec<User>, StorageError> {
-        let users = sqlx::query_as!(
-        User,
-        r#"
-        SELECT
-            id as "id!",
-            username as "username!",
-            email as "email!",
-            password_hash as "password_hash!",
-            created_at as "created_at!",
-            last_seen as "last_seen!"
-        FROM users
-        ORDER BY username
-        "#,
-    )
+    async fn attach_reactions(&self, viewer_id: Uuid, messages: &mut [Message]) -> Result<(), StorageError> {
+        let message_ids: Vec<Uuid> = messages.iter().map(|message| message.id).collect();
+        let mut reactions = self.get_reaction_counts(viewer_id, &message_ids).await?;
+        for message in messages {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    /// Reactions per message and emoji, in the order each emoji was first
+    /// used on the message.
+    async fn get_reaction_counts(
+        &self,
+        viewer_id: Uuid,
+        message_ids: &[Uuid],
+    ) -> Result<HashMap<Uuid, Vec<ReactionCount>>, StorageError> {
+        if message_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let rows = sqlx::query_as!(
+            DbReactionCount,
+            r#"
+            SELECT
+                message_id,
+                emoji,
+                COUNT(*) as "count!",
+                BOOL_OR(user_id = $2) as "reacted!"
+            FROM message_reactions
+            WHERE message_id = ANY($1)
+            GROUP BY message_id, emoji
+            ORDER BY MIN(created_at)
+            "#,
+            message_ids,
+            viewer_id,
+        )
             .fetch_all(&self.db_pool)
             .await
             .map_err(StorageError::Database)?;
 
-        Ok(users)
+        let mut reactions: HashMap<Uuid, Vec<ReactionCount>> = HashMap::new();
+        for row in rows {
+            reactions.entry(row.message_id).or_default().push(ReactionCount {
+                emoji: row.emoji,
+                count: row.count,
+                reacted: row.reacted,
+            });
+        }
+
+        Ok(reactions)
     }
 
-    pub async fn save_file(
+    /// Reacts to a message in a conversation the user belongs to. Deleted
+    /// messages cannot be reacted to.
+    pub async fn add_reaction(
         &self,
+        message_id: Uuid,
         user_id: Uuid,
-        filename: String,
-        data: Vec<u8>,
-    ) -> Result<PathBuf, StorageError> {
-        let user_dir = self.file_storage_path.join(user_id.to_string());
-        println!("Creating user directory at: {:?}", user_dir);
+        emoji: &str,
+    ) -> Result<ReactionUpdate, StorageError> {
+        let message = self.reactable_message(message_id, user_id).await?;
 
-        tokio::fs::create_dir_all(&user_dir).await.map_err(|e| {
-            println!("Failed to create user directory: {}", e);
-            StorageError::FileSystem(e)
-        })?;
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO message_reactions (message_id, user_id, emoji, created_at)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT DO NOTHING
+            "#,
+            message_id,
+            user_id,
+            emoji,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
 
-        let file_path = user_dir.join(&filename);
-        println!("Writing file to: {:?}", file_path);
+        Ok(ReactionUpdate {
+            conversation_id: message.conversation_id,
+            count: self.count_reactions(message_id, emoji).await?,
+            changed: result.rows_affected() > 0,
+        })
+    }
 
-        tokio::fs::write(&file_path, data).await.map_err(|e| {
-            println!("Failed to write file: {}", e);
-            StorageError::FileSystem(e)
-        })?;
+    pub async fn remove_reaction(
+        &self,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+    ) -> Result<ReactionUpdate, StorageError> {
+        let message = self.reactable_message(message_id, user_id).await?;
 
-        println!("Successfully wrote file at: {:?}", file_path);
-        Ok(file_path)
+        let result = sqlx::query!(
+            "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+            message_id,
+            user_id,
+            emoji,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(ReactionUpdate {
+            conversation_id: message.conversation_id,
+            count: self.count_reactions(message_id, emoji).await?,
+            changed: result.rows_affected() > 0,
+        })
     }
 
-    pub fn get_base_path(&self) -> PathBuf {
-        self.file_storage_path.clone()
+    async fn reactable_message(&self, message_id: Uuid, user_id: Uuid) -> Result<Message, StorageError> {
+        let message = self.get_message(message_id).await?;
+        if message.deleted_at.is_some() {
+            return Err(StorageError::NotFound);
+        }
+        if self.get_member_role(message.conversation_id, user_id).await?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+
+        Ok(message)
     }
 
-    pub async fn get_file_path(&self, user_id: Uuid, filename: &str) -> PathBuf {
-        let user_dir = self.file_storage_path.join(user_id.to_string());
-        let _ = tokio::fs::create_dir_all(&user_dir).await;
-        user_dir.join(filename)
+    async fn count_reactions(&self, message_id: Uuid, emoji: &str) -> Result<i64, StorageError> {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM message_reactions WHERE message_id = $1 AND emoji = $2"#,
+            message_id,
+            emoji,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
     }
 
-    pub async fn mark_messages_as_read(
+    /// Loads one page of a conversation's history, newest first, strictly older
+    /// than `before`. Keyset pagination on `(created_at, id)` keeps pages stable
+    /// while new messages arrive. Reactions say whether `viewer_id` is among
+    /// the reactors.
+    pub async fn get_conversation_messages(
+        &self,
+        conversation_id: Uuid,
+        viewer_id: Uuid,
+        before: Option<&MessageCursor>,
+        limit: i64,
+    ) -> Result<MessagePage, StorageError> {
+        let db_messages = sqlx::query_as!(
+            DbMessage,
+            r#"
+            SELECT
+                id as "id!",
+                conversation_id as "conversation_id!",
+                sender_id as "sender_id!",
+                receiver_id,
+                content,
+                content_type as "content_type!: serde_json::Value",
+                created_at as "created_at!",
+                (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+                (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+                edited_at,
+                deleted_at
+            FROM messages
+            WHERE conversation_id = $1
+              AND ($2::timestamptz IS NULL OR (created_at, id) < ($2, $3))
+            ORDER BY created_at DESC, id DESC
+            LIMIT $4
+            "#,
+            conversation_id,
+            before.map(|cursor| cursor.created_at),
+            before.map(|cursor| cursor.id),
+            limit,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        let full_page = db_messages.len() as i64 == limit;
+        let next_cursor = db_messages
+            .last()
+            .filter(|_| full_page)
+            .map(|row| MessageCursor { created_at: row.created_at, id: row.id }.encode());
+
+        let mut messages: Vec<Message> = db_messages
+            .into_iter()
+            .filter_map(DbMessage::into_message)
+            .collect();
+        self.attach_reactions(viewer_id, &mut messages).await?;
+
+        Ok(MessagePage { messages, next_cursor })
+    }
+
+    /// Full-text search over messages in conversations the user belongs to,
+    /// best matches first. `query` uses web search syntax (quoted phrases,
+    /// `or`, `-excluded`); snippets wrap matches in `<mark>` tags.
+    pub async fn search_messages(
         &self,
         user_id: Uuid,
-        message_ids: &[Uuid],
-    ) -> Result<(), StorageError> {
-        sqlx::query!(
+        query: &str,
+        filters: &SearchFilters,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<SearchHit>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbSearchHit,
             r#"
-            UPDATE messages
-            SET read_at = NOW()
-            WHERE id = ANY($1) AND receiver_id = $2
+            SELECT
+                m.id,
+                m.conversation_id,
+                m.sender_id,
+                m.receiver_id,
+                m.content,
+                m.content_type,
+                m.created_at,
+                (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = m.id) as delivered_at,
+                (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = m.id) as read_at,
+                m.edited_at,
+                m.deleted_at,
+                ts_rank(m.content_tsv, q) as "rank!",
+                ts_headline(
+                    'simple',
+                    m.content,
+                    q,
+                    'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=20, MinWords=5'
+                ) as "snippet!"
+            FROM messages m, websearch_to_tsquery('simple', $2) q
+            WHERE m.content_tsv @@ q
+              AND m.deleted_at IS NULL
+              AND m.conversation_id IN (
+                  SELECT conversation_id FROM conversation_members WHERE user_id = $1
+              )
+              AND ($3::uuid IS NULL OR m.sender_id = $3)
+              AND ($4::uuid IS NULL OR m.conversation_id = $4)
+              AND ($5::timestamptz IS NULL OR m.created_at < $5)
+              AND ($6::timestamptz IS NULL OR m.created_at > $6)
+            ORDER BY ts_rank(m.content_tsv, q) DESC, m.created_at DESC
+            LIMIT $7 OFFSET $8
             "#,
-            message_ids,
             user_id,
+            query,
+            filters.sender_id,
+            filters.conversation_id,
+            filters.before,
+            filters.after,
+            limit,
+            offset,
         )
-            .execute(&self.db_pool)
+            .fetch_all(&self.db_pool)
             .await
             .map_err(StorageError::Database)?;
 
-        Ok(())
+        let hits = rows
+            .into_iter()
+            .filter_map(|row| {
+                let message = DbMessage {
+                    id: row.id,
+                    conversation_id: row.conversation_id,
+                    sender_id: row.sender_id,
+                    receiver_id: row.receiver_id,
+                    content: row.content,
+                    content_type: row.content_type,
+                    created_at: row.created_at,
+                    delivered_at: row.delivered_at,
+                    read_at: row.read_at,
+                    edited_at: row.edited_at,
+                    deleted_at: row.deleted_at,
+                }
+                    .into_message()?;
+                Some(SearchHit { message, rank: row.rank, snippet: row.snippet })
+            })
+            .collect();
+
+        Ok(hits)
     }
 
-    pub async fn get_unread_messages_count(
+    /// Finds the conversation a history request refers to: the id may name a
+    /// conversation the user belongs to, or another user whose direct
+    /// conversation with them is wanted.
+    pub async fn find_conversation_for(
         &self,
         user_id: Uuid,
-    ) -> Result<i64, StorageError> {
-        let result = sqlx::query!(
+        peer_or_id: Uuid,
+    ) -> Result<Option<Conversation>, StorageError> {
+        match self.get_conversation(peer_or_id).await {
+            Ok(conversation) if conversation.has_member(user_id) => return Ok(Some(conversation)),
+            Ok(_) => return Err(StorageError::PermissionDenied),
+            Err(StorageError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        let direct_key = direct_key(user_id, peer_or_id);
+        let conversation_id = sqlx::query_scalar!(
+            "SELECT id FROM conversations WHERE direct_key = $1",
+            direct_key
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        match conversation_id {
+            Some(conversation_id) => Ok(Some(self.get_conversation(conversation_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_message(&self, message_id: Uuid) -> Result<Message, StorageError> {
+        sqlx::query_as!(
+            DbMessage,
             r#"
-            SELECT COUNT(*) as count
+            SELECT id, conversation_id, sender_id, receiver_id, content,
+                   content_type, created_at,
+                   (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+                   (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+                   edited_at, deleted_at
             FROM messages
-            WHERE receiver_id = $1 AND read_at IS NULL
+            WHERE id = $1
             "#,
-            user_id,
+            message_id
         )
-            .fetch_one(&self.db_pool)
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .and_then(DbMessage::into_message)
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// Replaces the content of a text message, keeping the previous content as
+    /// a revision. Only the sender may edit, and deleted messages stay deleted.
+    pub async fn edit_message(
+        &self,
+        message_id: Uuid,
+        editor_id: Uuid,
+        content: String,
+    ) -> Result<Message, StorageError> {
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        let current = Self::lock_message(&mut tx, message_id).await?;
+        if current.sender_id != editor_id || !matches!(current.content_type, MessageType::Text) {
+            return Err(StorageError::PermissionDenied);
+        }
+        if current.deleted_at.is_some() {
+            return Err(StorageError::NotFound);
+        }
+
+        Self::insert_revision(&mut tx, message_id, &current.content).await?;
+
+        let updated = sqlx::query_as!(
+            DbMessage,
+            r#"
+            UPDATE messages
+            SET content = $2, edited_at = NOW()
+            WHERE id = $1
+            RETURNING id, conversation_id, sender_id, receiver_id, content,
+                      content_type, created_at,
+                      (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+                      (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+                      edited_at, deleted_at
+            "#,
+            message_id,
+            content,
+        )
+            .fetch_one(&mut *tx)
             .await
             .map_err(StorageError::Database)?;
 
-        Ok(result.count.unwrap_or(0))
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        updated.into_message().ok_or(StorageError::NotFound)
     }
-}
 
-impl Clone for Storage {
-    fn clone(&self) -> Self {
-        Self {
-            db_pool: self.db_pool.clone(),
-            file_storage_path: self.file_storage_path.clone(),
+    /// Soft-deletes a message: its content is moved into the revision history
+    /// and the message itself is kept as an empty tombstone.
+    pub async fn delete_message(
+        &self,
+        message_id: Uuid,
+        actor_id: Uuid,
+    ) -> Result<Message, StorageError> {
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        let current = Self::lock_message(&mut tx, message_id).await?;
+        if current.sender_id != actor_id {
+            return Err(StorageError::PermissionDenied);
         }
+
+        let deleted = Self::soft_delete_message(&mut tx, current).await?;
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        Ok(deleted)
     }
-}
\ No newline at end of file
+
+    /// Deletes any message on a moderator's behalf. The content survives as a
+    /// revision, like with a deletion by the sender.
+    pub async fn remove_message(&self, message_id: Uuid) -> Result<Message, StorageError> {
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        let current = Self::lock_message(&mut tx, message_id).await?;
+        let deleted = Self::soft_delete_message(&mut tx, current).await?;
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        Ok(deleted)
+    }
+
+    async fn soft_delete_message(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        current: Message,
+    ) -> Result<Message, StorageError> {
+        if current.deleted_at.is_some() {
+            return Err(StorageError::NotFound);
+        }
+
+        Self::insert_revision(tx, current.id, &current.content).await?;
+
+        sqlx::query!("DELETE FROM message_reactions WHERE message_id = $1", current.id)
+            .execute(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        let deleted = sqlx::query_as!(
+            DbMessage,
+            r#"
+            UPDATE messages
+            SET content = '', deleted_at = NOW()
+            WHERE id = $1
+            RETURNING id, conversation_id, sender_id, receiver_id, content,
+                      content_type, created_at,
+                      (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+                      (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+                      edited_at, deleted_at
+            "#,
+            current.id,
+        )
+            .fetch_one(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        deleted.into_message().ok_or(StorageError::NotFound)
+    }
+
+    pub async fn get_message_revisions(
+        &self,
+        message_id: Uuid,
+    ) -> Result<Vec<MessageRevision>, StorageError> {
+        sqlx::query_as!(
+            MessageRevision,
+            r#"
+            SELECT id, message_id, content, created_at
+            FROM message_revisions
+            WHERE message_id = $1
+            ORDER BY created_at
+            "#,
+            message_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    async fn lock_message(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        message_id: Uuid,
+    ) -> Result<Message, StorageError> {
+        sqlx::query_as!(
+            DbMessage,
+            r#"
+            SELECT id, conversation_id, sender_id, receiver_id, content,
+                   content_type, created_at,
+                   (SELECT MIN(r.delivered_at) FROM message_receipts r WHERE r.message_id = messages.id) as delivered_at,
+                   (SELECT MIN(r.read_at) FROM message_receipts r WHERE r.message_id = messages.id) as read_at,
+                   edited_at, deleted_at
+            FROM messages
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+            message_id
+        )
+            .fetch_optional(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?
+            .and_then(DbMessage::into_message)
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn insert_revision(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        message_id: Uuid,
+        content: &str,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO message_revisions (id, message_id, content, created_at)
+            VALUES ($1, $2, $3, NOW())
+            "#,
+            Uuid::new_v4(),
+            message_id,
+            content,
+        )
+            .execute(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    pub async fn create_conversation(
+        &self,
+        creator_id: Uuid,
+        kind: ConversationKind,
+        title: Option<String>,
+        member_ids: &[Uuid],
+    ) -> Result<Conversation, StorageError> {
+        if kind == ConversationKind::Direct {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        // Nobody is put in a conversation with someone on either side of a
+        // block with them.
+        let mut everyone = member_ids.to_vec();
+        everyone.push(creator_id);
+        if self.is_blocked_between_any(&everyone, &everyone).await? {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        let conversation_id = Uuid::new_v4();
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO conversations (id, kind, title, created_by, created_at)
+            VALUES ($1, $2, $3, $4, NOW())
+            "#,
+            conversation_id,
+            kind.as_str(),
+            title,
+            creator_id,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO conversation_members (conversation_id, user_id, role, joined_at)
+            VALUES ($1, $2, $3, NOW())
+            "#,
+            conversation_id,
+            creator_id,
+            MemberRole::Owner.as_str(),
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO conversation_members (conversation_id, user_id, role, joined_at)
+            SELECT $1, member_id, $3, NOW()
+            FROM UNNEST($2::uuid[]) AS member_id
+            ON CONFLICT DO NOTHING
+            "#,
+            conversation_id,
+            member_ids,
+            MemberRole::Member.as_str(),
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        self.get_conversation(conversation_id).await
+    }
+
+    /// Returns the one-to-one conversation between two users, creating it on
+    /// first contact.
+    pub async fn get_or_create_direct_conversation(
+        &self,
+        user_id: Uuid,
+        peer_id: Uuid,
+    ) -> Result<Conversation, StorageError> {
+        let direct_key = direct_key(user_id, peer_id);
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO conversations (id, kind, direct_key, created_by, created_at)
+            VALUES ($1, $2, $3, $4, NOW())
+            ON CONFLICT (direct_key) DO NOTHING
+            "#,
+            Uuid::new_v4(),
+            ConversationKind::Direct.as_str(),
+            direct_key,
+            user_id,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        let conversation_id = sqlx::query_scalar!(
+            "SELECT id FROM conversations WHERE direct_key = $1",
+            direct_key
+        )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO conversation_members (conversation_id, user_id, role, joined_at)
+            SELECT $1, member_id, $3, NOW()
+            FROM UNNEST($2::uuid[]) AS member_id
+            ON CONFLICT DO NOTHING
+            "#,
+            conversation_id,
+            &[user_id, peer_id][..],
+            MemberRole::Member.as_str(),
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        self.get_conversation(conversation_id).await
+    }
+
+    /// Resolves where a new message from `sender_id` goes: an explicit
+    /// conversation the sender belongs to, or the direct conversation with
+    /// `receiver_id`.
+    pub async fn resolve_conversation(
+        &self,
+        sender_id: Uuid,
+        conversation_id: Option<Uuid>,
+        receiver_id: Option<Uuid>,
+    ) -> Result<Conversation, StorageError> {
+        let conversation = match (conversation_id, receiver_id) {
+            (Some(conversation_id), _) => self.get_conversation(conversation_id).await?,
+            (None, Some(receiver_id)) => {
+                if self.is_blocked_between(sender_id, receiver_id).await? {
+                    return Err(StorageError::PermissionDenied);
+                }
+                self.get_or_create_direct_conversation(sender_id, receiver_id).await?
+            }
+            (None, None) => return Err(StorageError::NotFound),
+        };
+
+        if !conversation.has_member(sender_id) {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        // A block in either direction closes the direct conversation both ways.
+        if let Some(peer_id) = conversation.direct_peer(sender_id) {
+            if peer_id != sender_id && self.is_blocked_between(sender_id, peer_id).await? {
+                return Err(StorageError::PermissionDenied);
+            }
+        }
+
+        Ok(conversation)
+    }
+
+    pub async fn get_conversation(&self, conversation_id: Uuid) -> Result<Conversation, StorageError> {
+        let row = sqlx::query_as!(
+            DbConversation,
+            r#"
+            SELECT id, kind, title, created_by, created_at
+            FROM conversations
+            WHERE id = $1
+            "#,
+            conversation_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .ok_or(StorageError::NotFound)?;
+
+        self.attach_members(vec![row])
+            .await?
+            .pop()
+            .ok_or(StorageError::NotFound)
+    }
+
+    pub async fn get_user_conversations(&self, user_id: Uuid) -> Result<Vec<Conversation>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbConversation,
+            r#"
+            SELECT c.id, c.kind, c.title, c.created_by, c.created_at
+            FROM conversations c
+            JOIN conversation_members cm ON cm.conversation_id = c.id
+            WHERE cm.user_id = $1
+            ORDER BY c.created_at DESC
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        self.attach_members(rows).await
+    }
+
+    /// Joins a public channel. Groups can only be entered by being added by an
+    /// owner or admin.
+    pub async fn join_conversation(
+        &self,
+        conversation_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Conversation, StorageError> {
+        let conversation = self.get_conversation(conversation_id).await?;
+        if conversation.kind != ConversationKind::Channel {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        self.insert_member(conversation_id, user_id, MemberRole::Member).await?;
+        self.get_conversation(conversation_id).await
+    }
+
+    pub async fn add_conversation_member(
+        &self,
+        conversation_id: Uuid,
+        actor_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Conversation, StorageError> {
+        let conversation = self.get_conversation(conversation_id).await?;
+        let actor_role = self
+            .get_member_role(conversation_id, actor_id)
+            .await?
+            .ok_or(StorageError::NotFound)?;
+
+        if conversation.kind == ConversationKind::Direct || !actor_role.can_manage_members() {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        let member_ids: Vec<Uuid> = conversation.members.iter().map(|member| member.user_id).collect();
+        if self.is_blocked_between_any(&[user_id], &member_ids).await? {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        self.insert_member(conversation_id, user_id, MemberRole::Member).await?;
+        self.get_conversation(conversation_id).await
+    }
+
+    /// Leaves a group or channel. If the owner leaves, ownership passes to the
+    /// longest-standing admin, or failing that the longest-standing member.
+    pub async fn leave_conversation(
+        &self,
+        conversation_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), StorageError> {
+        let conversation = self.get_conversation(conversation_id).await?;
+        if conversation.kind == ConversationKind::Direct {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+        if !Self::remove_member(&mut tx, conversation_id, user_id).await? {
+            return Err(StorageError::NotFound);
+        }
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    /// Takes a user out of a group or channel, handing ownership on to the
+    /// longest-standing admin, or member, when the owner leaves. Returns
+    /// whether they were a member.
+    async fn remove_member(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        conversation_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<bool, StorageError> {
+        let result = sqlx::query!(
+            "DELETE FROM conversation_members WHERE conversation_id = $1 AND user_id = $2",
+            conversation_id,
+            user_id,
+        )
+            .execute(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE conversation_members
+            SET role = $2
+            WHERE conversation_id = $1
+              AND user_id = (
+                  SELECT user_id FROM conversation_members
+                  WHERE conversation_id = $1
+                  ORDER BY (role = $3) DESC, joined_at
+                  LIMIT 1
+              )
+              AND NOT EXISTS (
+                  SELECT 1 FROM conversation_members
+                  WHERE conversation_id = $1 AND role = $2
+              )
+            "#,
+            conversation_id,
+            MemberRole::Owner.as_str(),
+            MemberRole::Admin.as_str(),
+        )
+            .execute(&mut **tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(true)
+    }
+
+    pub async fn get_member_role(
+        &self,
+        conversation_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<MemberRole>, StorageError> {
+        let role = sqlx::query_scalar!(
+            "SELECT role FROM conversation_members WHERE conversation_id = $1 AND user_id = $2",
+            conversation_id,
+            user_id,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(role.and_then(|role| MemberRole::parse(&role)))
+    }
+
+    pub async fn get_conversation_member_ids(
+        &self,
+        conversation_id: Uuid,
+    ) -> Result<Vec<Uuid>, StorageError> {
+        sqlx::query_scalar!(
+            "SELECT user_id FROM conversation_members WHERE conversation_id = $1",
+            conversation_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    async fn insert_member(
+        &self,
+        conversation_id: Uuid,
+        user_id: Uuid,
+        role: MemberRole,
+    ) -> Result<(), StorageError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO conversation_members (conversation_id, user_id, role, joined_at)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT DO NOTHING
+            "#,
+            conversation_id,
+            user_id,
+            role.as_str(),
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    async fn attach_members(
+        &self,
+        rows: Vec<DbConversation>,
+    ) -> Result<Vec<Conversation>, StorageError> {
+        let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+
+        let members = sqlx::query_as!(
+            DbConversationMember,
+            r#"
+            SELECT conversation_id, user_id, role, joined_at
+            FROM conversation_members
+            WHERE conversation_id = ANY($1)
+            ORDER BY joined_at
+            "#,
+            &ids[..],
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        let mut members_by_conversation: HashMap<Uuid, Vec<ConversationMember>> = HashMap::new();
+        for member in members {
+            if let Some(role) = MemberRole::parse(&member.role) {
+                members_by_conversation
+                    .entry(member.conversation_id)
+                    .or_default()
+                    .push(ConversationMember {
+                        user_id: member.user_id,
+                        role,
+                        joined_at: member.joined_at,
+                    });
+            }
+        }
+
+        let conversations = rows
+            .into_iter()
+            .filter_map(|row| {
+                let kind = ConversationKind::parse(&row.kind)?;
+                Some(Conversation {
+                    id: row.id,
+                    kind,
+                    title: row.title,
+                    created_by: row.created_by,
+                    created_at: row.created_at,
+                    members: members_by_conversation.remove(&row.id).unwrap_or_default(),
+                })
+            })
+            .collect();
+
+        Ok(conversations)
+    }
+
+    pub async fn update_last_seen(&self, user_id: Uuid) -> Result<(), StorageError> {
+        sqlx::query!(
+            "UPDATE users SET last_seen = NOW() WHERE id = $1",
+            user_id
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    /// The status the user last chose, Online if they never picked one.
+    pub async fn get_presence_status(&self, user_id: Uuid) -> Result<PresenceStatus, StorageError> {
+        let status = sqlx::query_scalar!(
+            "SELECT status FROM user_presence WHERE user_id = $1",
+            user_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(status
+            .and_then(|status| PresenceStatus::parse(&status))
+            .unwrap_or(PresenceStatus::Online))
+    }
+
+    pub async fn set_presence_status(&self, user_id: Uuid, status: PresenceStatus) -> Result<(), StorageError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_presence (user_id, status, updated_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT (user_id) DO UPDATE SET status = EXCLUDED.status, updated_at = NOW()
+            "#,
+            user_id,
+            status.as_str(),
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    pub async fn set_last_seen_visibility(&self, user_id: Uuid, show_last_seen: bool) -> Result<(), StorageError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_presence (user_id, show_last_seen, updated_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT (user_id) DO UPDATE SET show_last_seen = EXCLUDED.show_last_seen, updated_at = NOW()
+            "#,
+            user_id,
+            show_last_seen,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    pub async fn get_last_seen_records(&self, user_ids: &[Uuid]) -> Result<Vec<LastSeenRecord>, StorageError> {
+        sqlx::query_as!(
+            LastSeenRecord,
+            r#"
+            SELECT u.id AS user_id, u.last_seen, COALESCE(p.show_last_seen, TRUE) AS "show_last_seen!"
+            FROM users u
+            LEFT JOIN user_presence p ON p.user_id = u.id
+            WHERE u.id = ANY($1)
+            "#,
+            user_ids
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Users allowed to follow someone's presence: their contacts and everyone
+    /// they share a conversation with, unless either side blocked the other.
+    pub async fn get_presence_audience(&self, user_id: Uuid) -> Result<Vec<Uuid>, StorageError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT audience.user_id AS "user_id!"
+            FROM (
+                SELECT other.user_id
+                FROM conversation_members mine
+                JOIN conversation_members other ON other.conversation_id = mine.conversation_id
+                WHERE mine.user_id = $1 AND other.user_id <> $1
+                UNION
+                SELECT contact_id FROM contacts WHERE user_id = $1
+            ) audience
+            WHERE NOT EXISTS (
+                SELECT 1 FROM blocked_users b
+                WHERE (b.blocker_id = $1 AND b.blocked_id = audience.user_id)
+                   OR (b.blocker_id = audience.user_id AND b.blocked_id = $1)
+            )
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Finds other users by username: prefix matches first, then fuzzy
+    /// (trigram) matches by similarity. Users on either side of a block with
+    /// the searcher are left out.
+    pub async fn search_users(
+        &self,
+        searcher_id: Uuid,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PublicProfile>, StorageError> {
+        sqlx::query_as!(
+            PublicProfile,
+            r#"
+            SELECT u.id, u.username, u.display_name, u.bio, u.avatar_path
+            FROM users u
+            WHERE u.id <> $1
+              AND u.deleted_at IS NULL
+              AND (lower(u.username) LIKE lower($2) || '%' ESCAPE '\' OR u.username % $3)
+              AND NOT EXISTS (
+                  SELECT 1 FROM blocked_users b
+                  WHERE (b.blocker_id = $1 AND b.blocked_id = u.id)
+                     OR (b.blocker_id = u.id AND b.blocked_id = $1)
+              )
+            ORDER BY (lower(u.username) LIKE lower($2) || '%' ESCAPE '\') DESC,
+                     similarity(u.username, $3) DESC,
+                     u.username
+            LIMIT $4 OFFSET $5
+            "#,
+            searcher_id,
+            escape_like(query),
+            query,
+            limit,
+            offset,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Someone's public profile, unless either of the two users blocked the
+    /// other.
+    pub async fn get_public_profile(
+        &self,
+        viewer_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<PublicProfile, StorageError> {
+        if self.is_blocked_between(viewer_id, user_id).await? {
+            return Err(StorageError::NotFound);
+        }
+
+        sqlx::query_as!(
+            PublicProfile,
+            "SELECT id, username, display_name, bio, avatar_path FROM users WHERE id = $1",
+            user_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .ok_or(StorageError::NotFound)
+    }
+
+    pub async fn update_profile(
+        &self,
+        user_id: Uuid,
+        display_name: Option<String>,
+        bio: Option<String>,
+        avatar_path: Option<String>,
+    ) -> Result<User, StorageError> {
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users
+            SET display_name = $2, bio = $3, avatar_path = $4
+            WHERE id = $1
+            RETURNING id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                      avatar_path, deleted_at, role, suspended_at, suspension_reason
+            "#,
+            user_id,
+            display_name,
+            bio,
+            avatar_path,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// Deletes an account. Groups and channels are left first, in the same
+    /// transaction, so ownership passes on as if the user had left them.
+    /// Anonymizing keeps the account row, so messages and direct
+    /// conversations stay readable for the other side; cascading removes them
+    /// along with the user's uploaded files. Returns the sessions that ended,
+    /// whose connections are the caller's to close.
+    pub async fn delete_account(&self, user_id: Uuid, mode: AccountDeletionMode) -> Result<Vec<Uuid>, StorageError> {
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        let revoked = sqlx::query_scalar!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL RETURNING id",
+            user_id
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        let shared = sqlx::query_scalar!(
+            r#"
+            SELECT c.id
+            FROM conversations c
+            JOIN conversation_members cm ON cm.conversation_id = c.id
+            WHERE cm.user_id = $1 AND c.kind <> $2
+            "#,
+            user_id,
+            ConversationKind::Direct.as_str(),
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+        for conversation_id in shared {
+            Self::remove_member(&mut tx, conversation_id, user_id).await?;
+        }
+
+        match mode {
+            AccountDeletionMode::Anonymize => {
+                let placeholder = format!("deleted-{}", user_id.simple());
+                let placeholder_email = format!("{}@deleted.invalid", placeholder);
+                let result = sqlx::query!(
+                    r#"
+                    UPDATE users
+                    SET username = $2, email = $3, password_hash = '',
+                        display_name = NULL, bio = NULL, avatar_path = NULL, deleted_at = NOW()
+                    WHERE id = $1 AND deleted_at IS NULL
+                    "#,
+                    user_id,
+                    placeholder,
+                    placeholder_email,
+                )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                if result.rows_affected() == 0 {
+                    return Err(StorageError::NotFound);
+                }
+
+                sqlx::query!(
+                    "DELETE FROM contacts WHERE user_id = $1 OR contact_id = $1",
+                    user_id
+                )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!(
+                    "DELETE FROM contact_requests WHERE requester_id = $1 OR addressee_id = $1",
+                    user_id
+                )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!(
+                    "DELETE FROM blocked_users WHERE blocker_id = $1 OR blocked_id = $1",
+                    user_id
+                )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM user_presence WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM user_events WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM message_reactions WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM one_time_tokens WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+            }
+            AccountDeletionMode::Cascade => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM conversations
+                    WHERE kind = $2
+                      AND id IN (SELECT conversation_id FROM conversation_members WHERE user_id = $1)
+                    "#,
+                    user_id,
+                    ConversationKind::Direct.as_str(),
+                )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                sqlx::query!("DELETE FROM messages WHERE sender_id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                let result = sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(StorageError::Database)?;
+
+                if result.rows_affected() == 0 {
+                    return Err(StorageError::NotFound);
+                }
+            }
+        }
+
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        if mode == AccountDeletionMode::Cascade {
+            let user_dir = self.file_storage_path.join(user_id.to_string());
+            match tokio::fs::remove_dir_all(&user_dir).await {
+                Ok(()) => println!("Removed files of deleted user at: {:?}", user_dir),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => println!("Failed to remove files of deleted user at {:?}: {}", user_dir, e),
+            }
+        }
+
+        Ok(revoked)
+    }
+
+    /// Accounts for the admin user list, newest first. `query` matches the
+    /// start of a username or email address.
+    pub async fn list_users_for_admin(
+        &self,
+        query: Option<&str>,
+        suspended: Option<bool>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, StorageError> {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, username, email, email_verified_at, password_hash, created_at, display_name, bio,
+                   avatar_path, deleted_at, role, suspended_at, suspension_reason
+            FROM users
+            WHERE deleted_at IS NULL
+              AND ($1::text IS NULL
+                   OR lower(username) LIKE lower($1) || '%' ESCAPE '\'
+                   OR lower(email) LIKE lower($1) || '%' ESCAPE '\')
+              AND ($2::bool IS NULL OR (suspended_at IS NOT NULL) = $2)
+            ORDER BY created_at DESC
+            LIMIT $3 OFFSET $4
+            "#,
+            query.map(escape_like),
+            suspended,
+            limit,
+            offset,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Grants the admin role to the accounts with the given addresses and
+    /// returns how many changed.
+    pub async fn promote_admins(&self, emails: &[String]) -> Result<u64, StorageError> {
+        let result = sqlx::query!(
+            "UPDATE users SET role = $2 WHERE email = ANY($1) AND role <> $2 AND deleted_at IS NULL",
+            emails,
+            UserRole::Admin.as_str(),
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_server_stats(&self) -> Result<ServerStats, StorageError> {
+        sqlx::query_as!(
+            ServerStats,
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users WHERE deleted_at IS NULL) AS "users!",
+                (SELECT COUNT(*) FROM users WHERE suspended_at IS NOT NULL AND deleted_at IS NULL) AS "suspended_users!",
+                (SELECT COUNT(*) FROM users WHERE role = $1 AND deleted_at IS NULL) AS "admins!",
+                (SELECT COUNT(*) FROM messages WHERE deleted_at IS NULL) AS "messages!",
+                (SELECT COUNT(*) FROM messages WHERE created_at > NOW() - INTERVAL '24 hours') AS "messages_last_24h!",
+                (SELECT COUNT(*) FROM conversations) AS "conversations!"
+            "#,
+            UserRole::Admin.as_str(),
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Walks the upload directory and adds up what is stored in it.
+    pub async fn get_storage_usage(&self) -> Result<StorageUsage, StorageError> {
+        let mut usage = StorageUsage::default();
+        let mut pending = vec![self.file_storage_path.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await.map_err(StorageError::FileSystem)?;
+            while let Some(entry) = entries.next_entry().await.map_err(StorageError::FileSystem)? {
+                let metadata = entry.metadata().await.map_err(StorageError::FileSystem)?;
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    usage.files += 1;
+                    usage.bytes += metadata.len();
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    pub async fn get_user_id_by_username(&self, username: &str) -> Result<Uuid, StorageError> {
+        sqlx::query_scalar!("SELECT id FROM users WHERE username = $1 AND deleted_at IS NULL", username)
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// Asks `addressee_id` to become a contact of `requester_id`. A pending
+    /// request in the other direction is accepted instead, and repeating a
+    /// pending request returns it unchanged.
+    pub async fn create_contact_request(
+        &self,
+        requester_id: Uuid,
+        addressee_id: Uuid,
+    ) -> Result<ContactRequest, StorageError> {
+        if requester_id == addressee_id {
+            return Err(StorageError::PermissionDenied);
+        }
+        self.ensure_user_exists(addressee_id).await?;
+        if self.is_blocked_between(requester_id, addressee_id).await? {
+            return Err(StorageError::PermissionDenied);
+        }
+        if self.are_contacts(requester_id, addressee_id).await? {
+            return Err(StorageError::Conflict);
+        }
+
+        if let Some(reverse) = self.get_pending_contact_request(addressee_id, requester_id).await? {
+            return self.respond_to_contact_request(reverse.id, requester_id, true).await;
+        }
+
+        let inserted = sqlx::query_as!(
+            DbContactRequest,
+            r#"
+            INSERT INTO contact_requests (id, requester_id, addressee_id, status, created_at)
+            VALUES ($1, $2, $3, 'pending', NOW())
+            ON CONFLICT (requester_id, addressee_id) WHERE status = 'pending' DO NOTHING
+            RETURNING id, requester_id, addressee_id, status, created_at, responded_at
+            "#,
+            Uuid::new_v4(),
+            requester_id,
+            addressee_id,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        match inserted {
+            Some(row) => row.into_contact_request().ok_or(StorageError::NotFound),
+            None => self
+                .get_pending_contact_request(requester_id, addressee_id)
+                .await?
+                .ok_or(StorageError::NotFound),
+        }
+    }
+
+    /// Accepts or declines a pending request addressed to `addressee_id`.
+    /// Accepting makes both users contacts of each other.
+    pub async fn respond_to_contact_request(
+        &self,
+        request_id: Uuid,
+        addressee_id: Uuid,
+        accept: bool,
+    ) -> Result<ContactRequest, StorageError> {
+        let status = if accept {
+            ContactRequestStatus::Accepted
+        } else {
+            ContactRequestStatus::Declined
+        };
+
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        let request = sqlx::query_as!(
+            DbContactRequest,
+            r#"
+            UPDATE contact_requests
+            SET status = $3, responded_at = NOW()
+            WHERE id = $1 AND addressee_id = $2 AND status = 'pending'
+            RETURNING id, requester_id, addressee_id, status, created_at, responded_at
+            "#,
+            request_id,
+            addressee_id,
+            status.as_str(),
+        )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?
+            .and_then(DbContactRequest::into_contact_request)
+            .ok_or(StorageError::NotFound)?;
+
+        if accept {
+            sqlx::query!(
+                r#"
+                INSERT INTO contacts (user_id, contact_id, created_at)
+                VALUES ($1, $2, NOW()), ($2, $1, NOW())
+                ON CONFLICT DO NOTHING
+                "#,
+                request.requester_id,
+                request.addressee_id,
+            )
+                .execute(&mut *tx)
+                .await
+                .map_err(StorageError::Database)?;
+        }
+
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        Ok(request)
+    }
+
+    /// Pending requests the user sent or received, newest first.
+    pub async fn get_pending_contact_requests(&self, user_id: Uuid) -> Result<Vec<ContactRequest>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbContactRequest,
+            r#"
+            SELECT id, requester_id, addressee_id, status, created_at, responded_at
+            FROM contact_requests
+            WHERE (requester_id = $1 OR addressee_id = $1) AND status = 'pending'
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(rows.into_iter().filter_map(DbContactRequest::into_contact_request).collect())
+    }
+
+    pub async fn get_contacts(&self, user_id: Uuid) -> Result<Vec<Contact>, StorageError> {
+        sqlx::query_as!(
+            Contact,
+            r#"
+            SELECT c.contact_id AS user_id, u.username, c.created_at AS since
+            FROM contacts c
+            JOIN users u ON u.id = c.contact_id
+            WHERE c.user_id = $1
+            ORDER BY u.username
+            "#,
+            user_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    pub async fn remove_contact(&self, user_id: Uuid, contact_id: Uuid) -> Result<(), StorageError> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM contacts
+            WHERE (user_id = $1 AND contact_id = $2) OR (user_id = $2 AND contact_id = $1)
+            "#,
+            user_id,
+            contact_id,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        if result.rows_affected() == 0 {
+            return Err(StorageError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Blocks a user, which also ends any contact between the two and declines
+    /// pending requests in either direction.
+    pub async fn block_user(&self, blocker_id: Uuid, blocked_id: Uuid) -> Result<(), StorageError> {
+        if blocker_id == blocked_id {
+            return Err(StorageError::PermissionDenied);
+        }
+        self.ensure_user_exists(blocked_id).await?;
+
+        let mut tx = self.db_pool.begin().await.map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO blocked_users (blocker_id, blocked_id, created_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT DO NOTHING
+            "#,
+            blocker_id,
+            blocked_id,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM contacts
+            WHERE (user_id = $1 AND contact_id = $2) OR (user_id = $2 AND contact_id = $1)
+            "#,
+            blocker_id,
+            blocked_id,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        sqlx::query!(
+            r#"
+            UPDATE contact_requests
+            SET status = 'declined', responded_at = NOW()
+            WHERE status = 'pending'
+              AND ((requester_id = $1 AND addressee_id = $2) OR (requester_id = $2 AND addressee_id = $1))
+            "#,
+            blocker_id,
+            blocked_id,
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(StorageError::Database)?;
+
+        tx.commit().await.map_err(StorageError::Database)?;
+
+        Ok(())
+    }
+
+    pub async fn unblock_user(&self, blocker_id: Uuid, blocked_id: Uuid) -> Result<(), StorageError> {
+        let result = sqlx::query!(
+            "DELETE FROM blocked_users WHERE blocker_id = $1 AND blocked_id = $2",
+            blocker_id,
+            blocked_id,
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        if result.rows_affected() == 0 {
+            return Err(StorageError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_blocked_users(&self, blocker_id: Uuid) -> Result<Vec<Contact>, StorageError> {
+        sqlx::query_as!(
+            Contact,
+            r#"
+            SELECT b.blocked_id AS user_id, u.username, b.created_at AS since
+            FROM blocked_users b
+            JOIN users u ON u.id = b.blocked_id
+            WHERE b.blocker_id = $1
+            ORDER BY u.username
+            "#,
+            blocker_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Whether either user has blocked the other.
+    pub async fn is_blocked_between(&self, a: Uuid, b: Uuid) -> Result<bool, StorageError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM blocked_users
+                WHERE (blocker_id = $1 AND blocked_id = $2) OR (blocker_id = $2 AND blocked_id = $1)
+            ) AS "blocked!"
+            "#,
+            a,
+            b,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Whether anyone in `a` has blocked anyone in `b`, or the other way
+    /// round.
+    pub async fn is_blocked_between_any(&self, a: &[Uuid], b: &[Uuid]) -> Result<bool, StorageError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM blocked_users
+                WHERE (blocker_id = ANY($1) AND blocked_id = ANY($2))
+                   OR (blocker_id = ANY($2) AND blocked_id = ANY($1))
+            ) AS "blocked!"
+            "#,
+            a,
+            b,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    async fn are_contacts(&self, a: Uuid, b: Uuid) -> Result<bool, StorageError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM contacts WHERE user_id = $1 AND contact_id = $2
+            ) AS "contacts!"
+            "#,
+            a,
+            b,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    async fn get_pending_contact_request(
+        &self,
+        requester_id: Uuid,
+        addressee_id: Uuid,
+    ) -> Result<Option<ContactRequest>, StorageError> {
+        let row = sqlx::query_as!(
+            DbContactRequest,
+            r#"
+            SELECT id, requester_id, addressee_id, status, created_at, responded_at
+            FROM contact_requests
+            WHERE requester_id = $1 AND addressee_id = $2 AND status = 'pending'
+            "#,
+            requester_id,
+            addressee_id,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(row.and_then(DbContactRequest::into_contact_request))
+    }
+
+    async fn ensure_user_exists(&self, user_id: Uuid) -> Result<(), StorageError> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL) AS "exists!""#,
+            user_id
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        if exists {
+            Ok(())
+        } else {
+            Err(StorageError::NotFound)
+        }
+    }
+
+    pub async fn save_file(
+        &self,
+        user_id: Uuid,
+        filename: String,
+        data: Vec<u8>,
+    ) -> Result<PathBuf, StorageError> {
+        let user_dir = self.file_storage_path.join(user_id.to_string());
+        println!("Creating user directory at: {:?}", user_dir);
+
+        tokio::fs::create_dir_all(&user_dir).await.map_err(|e| {
+            println!("Failed to create user directory: {}", e);
+            StorageError::FileSystem(e)
+        })?;
+
+        let file_path = user_dir.join(&filename);
+        println!("Writing file to: {:?}", file_path);
+
+        tokio::fs::write(&file_path, data).await.map_err(|e| {
+            println!("Failed to write file: {}", e);
+            StorageError::FileSystem(e)
+        })?;
+
+        println!("Successfully wrote file at: {:?}", file_path);
+        Ok(file_path)
+    }
+
+    pub fn get_base_path(&self) -> PathBuf {
+        self.file_storage_path.clone()
+    }
+
+    pub async fn get_file_path(&self, user_id: Uuid, filename: &str) -> PathBuf {
+        let user_dir = self.file_storage_path.join(user_id.to_string());
+        let _ = tokio::fs::create_dir_all(&user_dir).await;
+        user_dir.join(filename)
+    }
+
+    /// Marks messages as read by `user_id`, who must be a member of their
+    /// conversation and not their sender. Returns the ones that changed state,
+    /// so their senders can be notified. A read message is implicitly
+    /// delivered.
+    pub async fn mark_messages_as_read(
+        &self,
+        user_id: Uuid,
+        message_ids: &[Uuid],
+    ) -> Result<Vec<ReceiptUpdate>, StorageError> {
+        sqlx::query_as!(
+            ReceiptUpdate,
+            r#"
+            WITH receipts AS (
+                INSERT INTO message_receipts (message_id, user_id, delivered_at, read_at)
+                SELECT m.id, $2, NOW(), NOW()
+                FROM messages m
+                JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.user_id = $2
+                WHERE m.id = ANY($1) AND m.sender_id <> $2
+                ON CONFLICT (message_id, user_id) DO UPDATE SET read_at = NOW()
+                WHERE message_receipts.read_at IS NULL
+                RETURNING message_id
+            )
+            SELECT m.id as "message_id!", m.sender_id as "sender_id!"
+            FROM receipts r
+            JOIN messages m ON m.id = r.message_id
+            "#,
+            message_ids,
+            user_id,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Records that messages reached a socket of `user_id`, under the same
+    /// rules as `mark_messages_as_read`. Returns the ones that changed state.
+    pub async fn mark_messages_as_delivered(
+        &self,
+        user_id: Uuid,
+        message_ids: &[Uuid],
+    ) -> Result<Vec<ReceiptUpdate>, StorageError> {
+        sqlx::query_as!(
+            ReceiptUpdate,
+            r#"
+            WITH receipts AS (
+                INSERT INTO message_receipts (message_id, user_id, delivered_at)
+                SELECT m.id, $2, NOW()
+                FROM messages m
+                JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.user_id = $2
+                WHERE m.id = ANY($1) AND m.sender_id <> $2
+                ON CONFLICT (message_id, user_id) DO NOTHING
+                RETURNING message_id
+            )
+            SELECT m.id as "message_id!", m.sender_id as "sender_id!"
+            FROM receipts r
+            JOIN messages m ON m.id = r.message_id
+            "#,
+            message_ids,
+            user_id,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Who a message has reached and been read by so far.
+    pub async fn get_message_receipts(
+        &self,
+        message_id: Uuid,
+    ) -> Result<Vec<MessageReceipt>, StorageError> {
+        sqlx::query_as!(
+            MessageReceipt,
+            r#"
+            SELECT user_id, delivered_at, read_at
+            FROM message_receipts
+            WHERE message_id = $1
+            ORDER BY delivered_at
+            "#,
+            message_id
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    /// Files a report about a message the reporter can see. Deleted messages
+    /// and one's own messages cannot be reported, and each user reports a
+    /// message at most once.
+    pub async fn create_report(
+        &self,
+        reporter_id: Uuid,
+        message_id: Uuid,
+        reason: String,
+    ) -> Result<Report, StorageError> {
+        let message = self.get_message(message_id).await?;
+        if message.deleted_at.is_some() {
+            return Err(StorageError::NotFound);
+        }
+        if self.get_member_role(message.conversation_id, reporter_id).await?.is_none() {
+            return Err(StorageError::NotFound);
+        }
+        if message.sender_id == reporter_id {
+            return Err(StorageError::PermissionDenied);
+        }
+
+        sqlx::query_as!(
+            DbReport,
+            r#"
+            INSERT INTO reports
+            (id, message_id, conversation_id, reporter_id, sender_id, message_content, reason, status, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW())
+            ON CONFLICT (message_id, reporter_id) DO NOTHING
+            RETURNING *
+            "#,
+            Uuid::new_v4(),
+            message.id,
+            message.conversation_id,
+            reporter_id,
+            message.sender_id,
+            message.content,
+            reason,
+            ReportStatus::Open.as_str(),
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .ok_or(StorageError::Conflict)?
+            .into_report()
+            .ok_or(StorageError::NotFound)
+    }
+
+    pub async fn get_report(&self, report_id: Uuid) -> Result<Report, StorageError> {
+        sqlx::query_as!(
+            DbReport,
+            "SELECT * FROM reports WHERE id = $1",
+            report_id
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .and_then(DbReport::into_report)
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// The moderation queue: reports in the given state, oldest first.
+    pub async fn get_reports(
+        &self,
+        status: ReportStatus,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Report>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbReport,
+            r#"
+            SELECT * FROM reports
+            WHERE status = $1
+            ORDER BY created_at, id
+            LIMIT $2 OFFSET $3
+            "#,
+            status.as_str(),
+            limit,
+            offset,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(rows.into_iter().filter_map(DbReport::into_report).collect())
+    }
+
+    /// Closes every open report about a message, since a decision about the
+    /// message answers all of them. Returns the reports that were closed.
+    pub async fn resolve_reports(
+        &self,
+        message_id: Uuid,
+        admin_id: Uuid,
+        status: ReportStatus,
+    ) -> Result<Vec<Report>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbReport,
+            r#"
+            UPDATE reports
+            SET status = $3, resolved_at = NOW(), resolved_by = $2
+            WHERE message_id = $1 AND status = $4
+            RETURNING *
+            "#,
+            message_id,
+            admin_id,
+            status.as_str(),
+            ReportStatus::Open.as_str(),
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(rows.into_iter().filter_map(DbReport::into_report).collect())
+    }
+
+    pub async fn record_moderation_action(
+        &self,
+        admin_id: Uuid,
+        action: ModerationActionKind,
+        target: ModerationTarget,
+        note: Option<String>,
+    ) -> Result<ModerationAction, StorageError> {
+        sqlx::query_as!(
+            DbModerationAction,
+            r#"
+            INSERT INTO moderation_actions
+            (id, admin_id, action, target_user_id, message_id, report_id, note, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())
+            RETURNING *
+            "#,
+            Uuid::new_v4(),
+            admin_id,
+            action.as_str(),
+            target.user_id,
+            target.message_id,
+            target.report_id,
+            note,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?
+            .into_moderation_action()
+            .ok_or(StorageError::NotFound)
+    }
+
+    /// The audit trail, newest first, optionally only about one user.
+    pub async fn get_moderation_actions(
+        &self,
+        target_user_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ModerationAction>, StorageError> {
+        let rows = sqlx::query_as!(
+            DbModerationAction,
+            r#"
+            SELECT * FROM moderation_actions
+            WHERE $1::uuid IS NULL OR target_user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2 OFFSET $3
+            "#,
+            target_user_id,
+            limit,
+            offset,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(rows.into_iter().filter_map(DbModerationAction::into_moderation_action).collect())
+    }
+
+    /// Appends an event to the user's delivery log and returns its sequence
+    /// number. The log is what gets replayed to clients that were offline.
+    pub async fn append_user_event(
+        &self,
+        user_id: Uuid,
+        event: &serde_json::Value,
+    ) -> Result<i64, StorageError> {
+        let seq = sqlx::query_scalar!(
+            r#"
+            INSERT INTO user_events (user_id, event, created_at)
+            VALUES ($1, $2, NOW())
+            RETURNING seq
+            "#,
+            user_id,
+            event,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(seq)
+    }
+
+    pub async fn get_user_events_since(
+        &self,
+        user_id: Uuid,
+        after_seq: i64,
+        limit: i64,
+    ) -> Result<Vec<DbUserEvent>, StorageError> {
+        sqlx::query_as!(
+            DbUserEvent,
+            r#"
+            SELECT seq, event
+            FROM user_events
+            WHERE user_id = $1 AND seq > $2
+            ORDER BY seq
+            LIMIT $3
+            "#,
+            user_id,
+            after_seq,
+            limit,
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    pub async fn get_user_event(
+        &self,
+        user_id: Uuid,
+        seq: i64,
+    ) -> Result<Option<serde_json::Value>, StorageError> {
+        sqlx::query_scalar!(
+            "SELECT event FROM user_events WHERE user_id = $1 AND seq = $2",
+            user_id,
+            seq,
+        )
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)
+    }
+
+    pub async fn get_latest_event_seq(&self, user_id: Uuid) -> Result<i64, StorageError> {
+        let seq = sqlx::query_scalar!(
+            "SELECT MAX(seq) FROM user_events WHERE user_id = $1",
+            user_id
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(seq.unwrap_or(0))
+    }
+
+    pub async fn prune_user_events(&self, older_than: DateTime<Utc>) -> Result<u64, StorageError> {
+        let result = sqlx::query!(
+            "DELETE FROM user_events WHERE created_at < $1",
+            older_than
+        )
+            .execute(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_unread_messages_count(
+        &self,
+        user_id: Uuid,
+    ) -> Result<i64, StorageError> {
+        let result = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as count
+            FROM messages m
+            JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.user_id = $1
+            WHERE m.sender_id <> $1
+              AND m.deleted_at IS NULL
+              AND NOT EXISTS (
+                  SELECT 1 FROM message_receipts r
+                  WHERE r.message_id = m.id AND r.user_id = $1 AND r.read_at IS NOT NULL
+              )
+            "#,
+            user_id,
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(StorageError::Database)?;
+
+        Ok(result.count.unwrap_or(0))
+    }
+}
+
+impl Clone for Storage {
+    fn clone(&self) -> Self {
+        Self {
+            db_pool: self.db_pool.clone(),
+            file_storage_path: self.file_storage_path.clone(),
+        }
+    }
+}
+
+fn direct_key(user_id: Uuid, peer_id: Uuid) -> String {
+    format!("{}:{}", user_id.min(peer_id), user_id.max(peer_id))
+}
+
+/// Escapes `LIKE` wildcards so user input only ever matches literally.
+fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
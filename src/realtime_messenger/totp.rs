@@ -0,0 +1,170 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+/// RFC 6238 parameters understood by every authenticator app.
+pub const DIGITS: u32 = 6;
+pub const PERIOD_SECS: u64 = 30;
+const SECRET_LENGTH: usize = 20;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// The code for one time step (RFC 4226 dynamic truncation).
+pub fn code_at(secret: &[u8], step: u64) -> String {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Checks a code against the current time step and one step either side to
+/// allow for clock drift. Returns the step it matched, so callers can refuse
+/// to accept the same code twice.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let current = unix_time / PERIOD_SECS;
+    (current.saturating_sub(1)..=current + 1)
+        .find(|step| constant_time_eq(code_at(secret, *step).as_bytes(), code.as_bytes()))
+}
+
+/// Link that authenticator apps read from a QR code.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        encode_base32(secret),
+        percent_encode(issuer),
+        DIGITS,
+        PERIOD_SECS,
+    )
+}
+
+/// Unpadded RFC 4648 base32, the form authenticator apps expect secrets in.
+pub fn encode_base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret from RFC 6238 appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn codes_match_rfc_6238_test_vectors() {
+        // Appendix B lists eight digits; the last six are what a six digit
+        // authenticator shows.
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (unix_time, code) in vectors {
+            assert_eq!(code_at(RFC_SECRET, unix_time / PERIOD_SECS), code, "at {}", unix_time);
+        }
+    }
+
+    #[test]
+    fn verify_allows_one_step_of_drift() {
+        let now = 1111111111;
+        let current = now / PERIOD_SECS;
+
+        assert_eq!(verify(RFC_SECRET, "050471", now), Some(current));
+        assert_eq!(verify(RFC_SECRET, &code_at(RFC_SECRET, current - 1), now), Some(current - 1));
+        assert_eq!(verify(RFC_SECRET, &code_at(RFC_SECRET, current + 1), now), Some(current + 1));
+        assert_eq!(verify(RFC_SECRET, &code_at(RFC_SECRET, current + 2), now), None);
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        assert_eq!(verify(RFC_SECRET, "", 59), None);
+        assert_eq!(verify(RFC_SECRET, "28708", 59), None);
+        assert_eq!(verify(RFC_SECRET, "94287082", 59), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648_test_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+
+        for (input, encoded) in vectors {
+            assert_eq!(encode_base32(input.as_bytes()), encoded, "for {:?}", input);
+        }
+    }
+
+    #[test]
+    fn otpauth_uri_escapes_issuer_and_account() {
+        assert_eq!(
+            otpauth_uri("Realtime Messenger", "ann@example.com", b"foo"),
+            "otpauth://totp/Realtime%20Messenger:ann%40example.com?secret=MZXW6\
+             &issuer=Realtime%20Messenger&algorithm=SHA1&digits=6&period=30",
+        );
+    }
+
+    #[test]
+    fn generated_secrets_differ() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), SECRET_LENGTH);
+        assert_ne!(secret, generate_secret());
+    }
+}
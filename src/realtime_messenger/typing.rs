@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+struct TypingState {
+    last_forwarded: Instant,
+    expires_at: Instant,
+}
+
+/// Who is typing in which conversation. Typing signals are forwarded at most
+/// once per `throttle` for each sender and conversation, and a sender who
+/// stops signalling for `timeout` is considered to have stopped typing.
+pub struct TypingTracker {
+    active: Mutex<HashMap<(Uuid, Uuid), TypingState>>,
+    throttle: Duration,
+    timeout: Duration,
+}
+
+impl TypingTracker {
+    pub fn new(throttle: Duration, timeout: Duration) -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            throttle,
+            timeout,
+        }
+    }
+
+    /// Records a typing signal and returns whether it should be forwarded.
+    pub fn typing(&self, sender_id: Uuid, conversation_id: Uuid) -> bool {
+        let now = Instant::now();
+        let mut active = self.active.lock().unwrap();
+        match active.get_mut(&(sender_id, conversation_id)) {
+            Some(state) => {
+                state.expires_at = now + self.timeout;
+                if now.duration_since(state.last_forwarded) < self.throttle {
+                    return false;
+                }
+                state.last_forwarded = now;
+                true
+            }
+            None => {
+                active.insert((sender_id, conversation_id), TypingState {
+                    last_forwarded: now,
+                    expires_at: now + self.timeout,
+                });
+                true
+            }
+        }
+    }
+
+    /// Returns whether the sender was typing, i.e. whether others need to be
+    /// told they stopped.
+    pub fn stop(&self, sender_id: Uuid, conversation_id: Uuid) -> bool {
+        self.active.lock().unwrap().remove(&(sender_id, conversation_id)).is_some()
+    }
+
+    /// Removes and returns the conversations the sender went quiet in.
+    pub fn take_expired(&self, sender_id: Uuid) -> Vec<Uuid> {
+        let now = Instant::now();
+        self.take_where(|(sender, _), state| *sender == sender_id && state.expires_at <= now)
+    }
+
+    /// Removes and returns every conversation the sender is typing in.
+    pub fn take_all(&self, sender_id: Uuid) -> Vec<Uuid> {
+        self.take_where(|(sender, _), _| *sender == sender_id)
+    }
+
+    fn take_where(&self, predicate: impl Fn(&(Uuid, Uuid), &TypingState) -> bool) -> Vec<Uuid> {
+        let mut active = self.active.lock().unwrap();
+        let taken: Vec<(Uuid, Uuid)> = active
+            .iter()
+            .filter(|(key, state)| predicate(key, state))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in &taken {
+            active.remove(key);
+        }
+        taken.into_iter().map(|(_, conversation_id)| conversation_id).collect()
+    }
+}
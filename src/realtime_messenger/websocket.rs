@@ -1,12 +1,21 @@
-use crate::realtime_messenger::models::{Message, User, MessageType};
-use futures::{FutureExt, StreamExt};
+use chrono::{DateTime, Utc};
+use crate::realtime_messenger::models::{ContactRequest, Conversation, Message, User, MessageType, Presence, PresenceStatus};
+use futures::{SinkExt, StreamExt};
+use crate::realtime_messenger::storage::ReceiptUpdate;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, RwLock};
 use uuid::Uuid;
 use warp::ws::{Message as WsMessage, WebSocket};
 use crate::realtime_messenger::Storage;
+use crate::realtime_messenger::storage::StorageError;
+use crate::realtime_messenger::pubsub::{Delivery, Envelope, Payload, PubSub};
+use crate::realtime_messenger::codec::{Codec, EncodedEvent};
+use crate::realtime_messenger::presence::PresenceTracker;
+use crate::realtime_messenger::typing::TypingTracker;
+use crate::realtime_messenger::outbound::{Frame, OutboundQueue, Priority, PushResult, QueueStats};
 
 #[derive(Debug)]
 pub enum WebSocketError {
@@ -14,147 +23,1329 @@ pub enum WebSocketError {
     MessageSendError,
 }
 
+/// Oldest protocol version still accepted at handshake. Version 1 clients
+/// send bare commands and get no replies.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// From version 2 every command travels in a `CommandFrame` and is answered
+/// with an `Ack` or an `Error`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Picks the version a connection will speak: the client's if supported,
+/// otherwise the newest this server knows. Clients that do not ask get
+/// version 1.
+pub fn negotiate_protocol(requested: Option<u32>) -> Option<u32> {
+    match requested {
+        None => Some(MIN_PROTOCOL_VERSION),
+        Some(version) if version < MIN_PROTOCOL_VERSION => None,
+        Some(version) => Some(version.min(PROTOCOL_VERSION)),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandFrame {
+    pub version: u32,
+    pub request_id: String,
+    pub command: WebSocketCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedFrame,
+    UnsupportedVersion,
+    InvalidCommand,
+    NotFound,
+    Forbidden,
+    Conflict,
+    Internal,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl CommandError {
+    fn new(code: ErrorCode, reason: &str) -> Self {
+        Self { code, reason: reason.to_string() }
+    }
+}
+
+impl From<StorageError> for CommandError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::NotFound => CommandError::new(ErrorCode::NotFound, "Not found"),
+            StorageError::PermissionDenied => CommandError::new(ErrorCode::Forbidden, "Permission denied"),
+            StorageError::Conflict => CommandError::new(ErrorCode::Conflict, "Conflict"),
+            StorageError::Database(_) | StorageError::FileSystem(_) => {
+                CommandError::new(ErrorCode::Internal, "Internal server error")
+            }
+        }
+    }
+}
+
+/// What an `Ack` reports back about a successful command.
+#[derive(Debug, Default)]
+struct CommandOutcome {
+    message_id: Option<Uuid>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl CommandOutcome {
+    fn message(message: &Message) -> Self {
+        Self { message_id: Some(message.id), created_at: Some(message.created_at) }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum WebSocketCommand {
     SendMessage {
         content: String,
-        receiver_id: Uuid,
+        #[serde(default)]
+        conversation_id: Option<Uuid>,
+        #[serde(default)]
+        receiver_id: Option<Uuid>,
         content_type: MessageType,
+        /// Client-generated id; resending a message with the same id is
+        /// acknowledged without storing or delivering it again.
+        #[serde(default)]
+        message_id: Option<Uuid>,
     },
     MarkAsRead {
         message_ids: Vec<Uuid>,
     },
+    MarkAsDelivered {
+        message_ids: Vec<Uuid>,
+    },
+    /// Sent while the user types. Either id names the conversation, like for
+    /// `SendMessage`.
     Typing {
-        receiver_id: Uuid,
+        #[serde(default)]
+        conversation_id: Option<Uuid>,
+        #[serde(default)]
+        receiver_id: Option<Uuid>,
+    },
+    StopTyping {
+        #[serde(default)]
+        conversation_id: Option<Uuid>,
+        #[serde(default)]
+        receiver_id: Option<Uuid>,
+    },
+    EditMessage {
+        message_id: Uuid,
+        content: String,
+    },
+    DeleteMessage {
+        message_id: Uuid,
+    },
+    React {
+        message_id: Uuid,
+        emoji: String,
+    },
+    Unreact {
+        message_id: Uuid,
+        emoji: String,
+    },
+    SetPresence {
+        status: PresenceStatus,
     },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum WebSocketEvent {
     MessageReceived(Message),
+    MessageEdited(Message),
+    MessageDeleted {
+        message_id: Uuid,
+        conversation_id: Uuid,
+    },
+    MessageDelivered {
+        message_ids: Vec<Uuid>,
+        user_id: Uuid,
+    },
     MessageRead {
         message_ids: Vec<Uuid>,
         user_id: Uuid,
     },
+    /// `count` is how many users have reacted with `emoji` after the change.
+    ReactionChanged {
+        message_id: Uuid,
+        conversation_id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+        added: bool,
+        count: i64,
+    },
     UserTyping {
         user_id: Uuid,
+        conversation_id: Uuid,
+    },
+    UserStoppedTyping {
+        user_id: Uuid,
+        conversation_id: Uuid,
+    },
+    PresenceChanged(Presence),
+    ContactRequestUpdated(ContactRequest),
+    ReplayComplete {
+        last_seq: i64,
+        truncated: bool,
+    },
+    Hello {
+        protocol_version: u32,
+    },
+    Ack {
+        request_id: String,
+        message_id: Option<Uuid>,
+        created_at: Option<DateTime<Utc>>,
+    },
+    Error {
+        request_id: Option<String>,
+        code: ErrorCode,
+        reason: String,
     },
-    UserOnline(Uuid),
-    UserOffline(Uuid),
 }
 
-type Users = Arc<RwLock<HashMap<Uuid, mpsc::UnboundedSender<Result<WsMessage, warp::Error>>>>>;
+impl WebSocketEvent {
+    /// Durable events are written to the recipient's event log before being
+    /// pushed, so they can be replayed to clients that missed them. Presence
+    /// and typing are only meaningful live.
+    fn is_durable(&self) -> bool {
+        matches!(
+            self,
+            WebSocketEvent::MessageReceived(_)
+                | WebSocketEvent::MessageEdited(_)
+                | WebSocketEvent::MessageDeleted { .. }
+                | WebSocketEvent::ReactionChanged { .. }
+                | WebSocketEvent::MessageDelivered { .. }
+                | WebSocketEvent::MessageRead { .. }
+                | WebSocketEvent::ContactRequestUpdated(_)
+        )
+    }
+
+    /// Presence and typing can be dropped for a slow consumer; the next
+    /// update supersedes them anyway.
+    fn priority(&self) -> Priority {
+        match self {
+            WebSocketEvent::UserTyping { .. }
+            | WebSocketEvent::UserStoppedTyping { .. }
+            | WebSocketEvent::PresenceChanged(_) => Priority::Droppable,
+            _ => Priority::Essential,
+        }
+    }
+
+    /// The chat message this event hands to a recipient, whose delivery is
+    /// recorded once the frame is written to their socket.
+    fn delivers(&self) -> Option<Uuid> {
+        match self {
+            WebSocketEvent::MessageReceived(message) => Some(message.id),
+            _ => None,
+        }
+    }
+}
+
+const MAX_REPLAY_EVENTS: i64 = 1000;
+/// Matches the width of `message_reactions.emoji`; enough for any emoji
+/// sequence, including skin tones and ZWJ joins.
+const MAX_REACTION_LENGTH: usize = 32;
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    /// How often the server pings each connection.
+    pub ping_interval: Duration,
+    /// How long after the last sign of life past a ping a connection is
+    /// considered dead and reaped.
+    pub pong_timeout: Duration,
+    /// Frames buffered per connection before the slow-consumer policy kicks in.
+    pub outbound_queue_capacity: usize,
+    /// How many times a connection may overflow its queue before it is
+    /// disconnected.
+    pub max_queue_overflows: u32,
+    /// Inactivity after which an online user is shown as away.
+    pub idle_timeout: Duration,
+    /// Minimum gap between typing signals forwarded for one sender and
+    /// conversation.
+    pub typing_throttle: Duration,
+    /// Silence after which a typing sender is announced as stopped.
+    pub typing_timeout: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            outbound_queue_capacity: 256,
+            max_queue_overflows: 32,
+            idle_timeout: Duration::from_secs(300),
+            typing_throttle: Duration::from_secs(2),
+            typing_timeout: Duration::from_secs(6),
+        }
+    }
+}
+
+struct Connection {
+    session_id: Uuid,
+    codec: Codec,
+    queue: Arc<OutboundQueue>,
+    close: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub user_id: Uuid,
+    pub connection_id: Uuid,
+    pub session_id: Uuid,
+    pub capacity: usize,
+    #[serde(flatten)]
+    pub queue: QueueStats,
+}
+
+/// Live connections of each user on this node, keyed by connection id so a
+/// user can be online from several tabs and devices at once.
+type Users = Arc<RwLock<HashMap<Uuid, HashMap<Uuid, Connection>>>>;
 
 pub struct WebSocketHandler {
+    config: WebSocketConfig,
+    node_id: Uuid,
     users: Users,
     storage: Arc<Storage>,
+    pubsub: Arc<dyn PubSub>,
+    presence: Arc<PresenceTracker>,
+    typing: TypingTracker,
 }
 
 impl WebSocketHandler {
-    pub fn new(storage: Storage) -> Self {
-        Self {
+    /// Creates the handler and starts delivering events published by other
+    /// server instances to this instance's connections.
+    pub fn new(storage: Storage, pubsub: Arc<dyn PubSub>, config: WebSocketConfig) -> Self {
+        let handler = Self {
+            presence: Arc::new(PresenceTracker::new(config.idle_timeout)),
+            typing: TypingTracker::new(config.typing_throttle, config.typing_timeout),
+            config,
+            node_id: Uuid::new_v4(),
             users: Arc::new(RwLock::new(HashMap::new())),
             storage: Arc::new(storage),
-        }
+            pubsub,
+        };
+
+        tokio::spawn(run_remote_deliveries(
+            handler.node_id,
+            handler.users.clone(),
+            handler.storage.clone(),
+            handler.presence.clone(),
+            handler.pubsub.clone(),
+            handler.pubsub.subscribe(),
+        ));
+
+        handler
     }
 
     pub async fn handle_connection(
         &self,
         user: &User,
+        session_id: Uuid,
+        protocol_version: u32,
+        codec: Codec,
+        last_seq: Option<i64>,
         ws: WebSocket,
     ) {
-        let (ws_sender, mut ws_receiver) = ws.split();
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (mut ws_sender, mut ws_receiver) = ws.split();
+        let queue = Arc::new(OutboundQueue::new(
+            self.config.outbound_queue_capacity,
+            self.config.max_queue_overflows,
+        ));
+        let (close_tx, mut close_rx) = oneshot::channel();
+        let connection_id = Uuid::new_v4();
 
-        self.users.write().await.insert(user.id, tx.clone());
+        let first_connection = {
+            let mut users = self.users.write().await;
+            let connections = users.entry(user.id).or_default();
+            connections.insert(connection_id, Connection {
+                session_id,
+                codec,
+                queue: queue.clone(),
+                close: close_tx,
+            });
+            connections.len() == 1
+        };
+
+        let writer_queue = queue.clone();
+        let (delivered_tx, mut delivered_rx) = mpsc::unbounded_channel();
+        let mut writer = tokio::spawn(async move {
+            while let Some(frame) = writer_queue.pop().await {
+                if let Err(e) = ws_sender.send(frame.message).await {
+                    println!("WebSocket write failed: {}", e);
+                    return;
+                }
+                if let Some(message_id) = frame.delivers {
+                    let _ = delivered_tx.send(message_id);
+                }
+            }
+            let _ = ws_sender.close().await;
+        });
+
+        if protocol_version >= 2 {
+            let hello = WebSocketEvent::Hello { protocol_version };
+            push(&queue, Priority::Essential, codec.encode(&hello));
+        }
+        self.replay_missed_events(user.id, last_seq, codec, &queue).await;
+        if first_connection {
+            let chosen = self.storage.get_presence_status(user.id).await.unwrap_or_else(|e| {
+                eprintln!("Failed to load presence status of {}: {:?}", user.id, e);
+                PresenceStatus::Online
+            });
+            let status = self.presence.connect(user.id, chosen).await;
+            if status != PresenceStatus::Offline {
+                self.broadcast_presence(user.id, status).await;
+            }
+        }
 
-        self.broadcast_user_status(user.id, true).await;
+        let mut heartbeat = tokio::time::interval(self.config.ping_interval);
+        heartbeat.tick().await;
+        let mut typing_sweep = tokio::time::interval(TYPING_SWEEP_INTERVAL);
+        let mut last_activity = Instant::now();
 
-        while let Some(result) = ws_receiver.next().await {
-            match result {
-                Ok(msg) => {
-                    if let Ok(text) = msg.to_str() {
-                        if let Ok(command) = serde_json::from_str::<WebSocketCommand>(text) {
-                            self.handle_command(user.id, command).await;
+        loop {
+            tokio::select! {
+                Ok(()) = &mut close_rx => {
+                    println!("Closing WebSocket of user {} after session revocation", user.id);
+                    break;
+                }
+                _ = &mut writer => {
+                    println!("WebSocket writer of user {} stopped, dropping connection", user.id);
+                    break;
+                }
+                Some(message_id) = delivered_rx.recv() => {
+                    let mut message_ids = vec![message_id];
+                    while let Ok(message_id) = delivered_rx.try_recv() {
+                        message_ids.push(message_id);
+                    }
+                    self.record_delivery(user.id, &message_ids).await;
+                }
+                _ = heartbeat.tick() => {
+                    if last_activity.elapsed() > self.config.ping_interval + self.config.pong_timeout {
+                        println!("Reaping unresponsive WebSocket of user {}", user.id);
+                        break;
+                    }
+                    let stats = queue.stats();
+                    if stats.depth * 2 >= queue.capacity() {
+                        println!(
+                            "Slow WebSocket consumer {} of user {}: {} queued, {} dropped, {} overflows",
+                            connection_id, user.id, stats.depth, stats.dropped, stats.overflows,
+                        );
+                    }
+                    push(&queue, Priority::Droppable, WsMessage::ping(Vec::new()));
+                    if let Some(status) = self.presence.check_idle(user.id).await {
+                        self.broadcast_presence(user.id, status).await;
+                    }
+                }
+                _ = typing_sweep.tick() => {
+                    for conversation_id in self.typing.take_expired(user.id) {
+                        self.send_typing(user.id, conversation_id, false).await;
+                    }
+                }
+                result = ws_receiver.next() => match result {
+                    Some(Ok(msg)) => {
+                        last_activity = Instant::now();
+                        if msg.is_close() {
+                            break;
+                        }
+                        if msg.is_text() || msg.is_binary() {
+                            if let Some(status) = self.presence.touch(user.id).await {
+                                self.broadcast_presence(user.id, status).await;
+                            }
+                            let reply = self
+                                .handle_frame(user.id, connection_id, protocol_version, codec, &msg)
+                                .await;
+                            if let Some(reply) = reply {
+                                push(&queue, Priority::Essential, codec.encode(&reply));
+                            }
                         }
                     }
+                    _ => break,
+                },
+            }
+        }
+
+        let last_connection = {
+            let mut users = self.users.write().await;
+            match users.get_mut(&user.id) {
+                Some(connections) => {
+                    connections.remove(&connection_id);
+                    if connections.is_empty() {
+                        users.remove(&user.id);
+                        true
+                    } else {
+                        false
+                    }
                 }
-                Err(_) => break,
+                None => true,
+            }
+        };
+        queue.close();
+
+        let mut message_ids = Vec::new();
+        while let Ok(message_id) = delivered_rx.try_recv() {
+            message_ids.push(message_id);
+        }
+        if !message_ids.is_empty() {
+            self.record_delivery(user.id, &message_ids).await;
+        }
+
+        if last_connection {
+            for conversation_id in self.typing.take_all(user.id) {
+                self.send_typing(user.id, conversation_id, false).await;
             }
         }
 
-        self.users.write().await.remove(&user.id);
-        self.broadcast_user_status(user.id, false).await;
+        let chosen = if last_connection {
+            self.presence.disconnect(user.id).await
+        } else {
+            self.presence.chosen(user.id).await
+        };
+        // Invisible users must not give themselves away through last-seen.
+        if chosen == Some(PresenceStatus::Invisible) {
+            return;
+        }
+
+        if let Err(e) = self.storage.update_last_seen(user.id).await {
+            eprintln!("Failed to update last_seen of {}: {:?}", user.id, e);
+        }
+
+        if last_connection {
+            self.broadcast_presence(user.id, PresenceStatus::Offline).await;
+        }
     }
 
-    async fn handle_command(&self, sender_id: Uuid, command: WebSocketCommand) {
+    /// Parses and runs one client frame, returning the `Ack` or `Error` owed to
+    /// the client. Version 1 clients are never answered.
+    async fn handle_frame(
+        &self,
+        sender_id: Uuid,
+        connection_id: Uuid,
+        protocol_version: u32,
+        codec: Codec,
+        message: &WsMessage,
+    ) -> Option<WebSocketEvent> {
+        if protocol_version < 2 {
+            match codec.decode::<WebSocketCommand>(message) {
+                Ok(command) => {
+                    if let Err(e) = self.handle_command(sender_id, connection_id, command).await {
+                        eprintln!("Command from {} failed: {:?}", sender_id, e);
+                    }
+                }
+                Err(e) => eprintln!("Ignoring malformed command from {}: {}", sender_id, e),
+            }
+            return None;
+        }
+
+        let frame = match codec.decode::<CommandFrame>(message) {
+            Ok(frame) => frame,
+            Err(reason) => {
+                let request_id = codec
+                    .decode::<serde_json::Value>(message)
+                    .ok()
+                    .and_then(|value| value.get("request_id")?.as_str().map(str::to_string));
+                return Some(WebSocketEvent::Error {
+                    request_id,
+                    code: ErrorCode::MalformedFrame,
+                    reason,
+                });
+            }
+        };
+
+        if frame.version != protocol_version {
+            return Some(WebSocketEvent::Error {
+                request_id: Some(frame.request_id),
+                code: ErrorCode::UnsupportedVersion,
+                reason: format!("Connection negotiated protocol version {}", protocol_version),
+            });
+        }
+
+        Some(match self.handle_command(sender_id, connection_id, frame.command).await {
+            Ok(outcome) => WebSocketEvent::Ack {
+                request_id: frame.request_id,
+                message_id: outcome.message_id,
+                created_at: outcome.created_at,
+            },
+            Err(e) => {
+                eprintln!("Command {} from {} failed: {:?}", frame.request_id, sender_id, e);
+                WebSocketEvent::Error {
+                    request_id: Some(frame.request_id),
+                    code: e.code,
+                    reason: e.reason,
+                }
+            }
+        })
+    }
+
+    async fn handle_command(
+        &self,
+        sender_id: Uuid,
+        connection_id: Uuid,
+        command: WebSocketCommand,
+    ) -> Result<CommandOutcome, CommandError> {
         match command {
-            WebSocketCommand::SendMessage { content, receiver_id, content_type } => {
-                println!("Processing message from {} to {}", sender_id, receiver_id);
+            WebSocketCommand::SendMessage { content, conversation_id, receiver_id, content_type, message_id } => {
+                let conversation = self
+                    .storage
+                    .resolve_conversation(sender_id, conversation_id, receiver_id)
+                    .await?;
+                println!("Processing message from {} to conversation {}", sender_id, conversation.id);
 
                 let message = Message {
-                    id: Uuid::new_v4(),
+                    id: message_id.unwrap_or_else(Uuid::new_v4),
+                    conversation_id: conversation.id,
                     sender_id,
-                    receiver_id,
+                    receiver_id: conversation.direct_peer(sender_id),
                     content,
                     content_type,
                     created_at: chrono::Utc::now(),
+                    delivered_at: None,
                     read_at: None,
+                    edited_at: None,
+                    deleted_at: None,
+                    reactions: Vec::new(),
                 };
 
-                if let Err(e) = self.storage.save_message(&message).await {
-                    eprintln!("Failed to save message: {:?}", e);
-                    return;
+                let (message, created) = match self.storage.save_message_idempotent(&message).await {
+                    Ok(saved) => saved,
+                    Err(StorageError::Conflict) => {
+                        return Err(CommandError::new(ErrorCode::Conflict, "Message id is already in use"));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                if !created {
+                    println!("Message {} was already stored, acknowledging resend", message.id);
+                    return Ok(CommandOutcome::message(&message));
                 }
                 println!("Message saved to database");
 
-                self.send_to_user(receiver_id, &WebSocketEvent::MessageReceived(message.clone())).await;
-                println!("Message sent to recipient");
+                let outcome = CommandOutcome::message(&message);
+                self.deliver_new_message(&conversation, message, Some(connection_id)).await;
+                Ok(outcome)
             },
             WebSocketCommand::MarkAsRead { message_ids } => {
-                if let Some(first_message) = message_ids.first() {
-                    if let Some(sender) = self.users.read().await.get(first_message) {
-                        let event = WebSocketEvent::MessageRead {
-                            message_ids,
-                            user_id: sender_id,
-                        };
-                        let _ = sender.send(Ok(WsMessage::text(serde_json::to_string(&event).unwrap())));
-                    }
+                let updates = self.storage.mark_messages_as_read(sender_id, &message_ids).await?;
+                for (original_sender, message_ids) in group_by_sender(updates) {
+                    self.send_to_user(
+                        original_sender,
+                        &WebSocketEvent::MessageRead { message_ids, user_id: sender_id },
+                    )
+                        .await;
+                }
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::MarkAsDelivered { message_ids } => {
+                self.mark_delivered(sender_id, &message_ids).await?;
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::Typing { conversation_id, receiver_id } => {
+                let conversation_id = self.typing_target(sender_id, conversation_id, receiver_id).await?;
+                if self.typing.typing(sender_id, conversation_id) {
+                    self.send_typing(sender_id, conversation_id, true).await;
+                }
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::StopTyping { conversation_id, receiver_id } => {
+                let conversation_id = self.typing_target(sender_id, conversation_id, receiver_id).await?;
+                if self.typing.stop(sender_id, conversation_id) {
+                    self.send_typing(sender_id, conversation_id, false).await;
+                }
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::EditMessage { message_id, content } => {
+                let message = self.edit_message(sender_id, message_id, content, Some(connection_id)).await?;
+                Ok(CommandOutcome::message(&message))
+            }
+            WebSocketCommand::DeleteMessage { message_id } => {
+                let message = self.delete_message(sender_id, message_id, Some(connection_id)).await?;
+                Ok(CommandOutcome::message(&message))
+            }
+            WebSocketCommand::React { message_id, emoji } => {
+                self.set_reaction(sender_id, message_id, emoji, true, Some(connection_id)).await?;
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::Unreact { message_id, emoji } => {
+                self.set_reaction(sender_id, message_id, emoji, false, Some(connection_id)).await?;
+                Ok(CommandOutcome::default())
+            }
+            WebSocketCommand::SetPresence { status } => {
+                if status == PresenceStatus::Offline {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidCommand,
+                        "Offline cannot be chosen, use invisible instead",
+                    ));
+                }
+
+                self.storage.set_presence_status(sender_id, status).await?;
+                if let Some(visible) = self.presence.set_chosen(sender_id, status).await {
+                    self.broadcast_presence(sender_id, visible).await;
                 }
+                Ok(CommandOutcome::default())
             }
-            WebSocketCommand::Typing { receiver_id } => {
-                self.send_to_user(
-                    receiver_id,
-                    &WebSocketEvent::UserTyping { user_id: sender_id },
-                )
-                    .await;
+        }
+    }
+
+    /// Records that messages reached `user_id` and tells their senders. Already
+    /// delivered messages are left alone, so clients acknowledging what the
+    /// server already recorded are harmless.
+    async fn mark_delivered(&self, user_id: Uuid, message_ids: &[Uuid]) -> Result<(), StorageError> {
+        let updates = self.storage.mark_messages_as_delivered(user_id, message_ids).await?;
+        for (original_sender, message_ids) in group_by_sender(updates) {
+            self.send_to_user(
+                original_sender,
+                &WebSocketEvent::MessageDelivered { message_ids, user_id },
+            )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Called with the messages the writer of one of the user's connections
+    /// has put on the wire.
+    async fn record_delivery(&self, user_id: Uuid, message_ids: &[Uuid]) {
+        if let Err(e) = self.mark_delivered(user_id, message_ids).await {
+            eprintln!("Failed to record delivery to {}: {:?}", user_id, e);
+        }
+    }
+
+    /// Lets both sides of a contact request know it was sent or answered.
+    pub async fn notify_contact_request(&self, request: &ContactRequest) {
+        let event = WebSocketEvent::ContactRequestUpdated(request.clone());
+        self.send_to_user(request.requester_id, &event).await;
+        self.send_to_user(request.addressee_id, &event).await;
+    }
+
+    /// Resolves the conversation a typing command is about without creating
+    /// one: typing to someone never messaged before has no audience yet.
+    async fn typing_target(
+        &self,
+        sender_id: Uuid,
+        conversation_id: Option<Uuid>,
+        receiver_id: Option<Uuid>,
+    ) -> Result<Uuid, CommandError> {
+        let target = conversation_id.or(receiver_id).ok_or_else(|| {
+            CommandError::new(ErrorCode::InvalidCommand, "Either conversation_id or receiver_id is required")
+        })?;
+
+        let conversation = self
+            .storage
+            .find_conversation_for(sender_id, target)
+            .await?
+            .ok_or_else(|| CommandError::new(ErrorCode::NotFound, "Conversation not found"))?;
+
+        if let Some(peer_id) = conversation.direct_peer(sender_id) {
+            if peer_id != sender_id && self.storage.is_blocked_between(sender_id, peer_id).await? {
+                return Err(CommandError::new(ErrorCode::Forbidden, "Permission denied"));
             }
         }
+
+        Ok(conversation.id)
+    }
+
+    /// Tells the other members of a conversation that the sender started or
+    /// stopped typing there.
+    async fn send_typing(&self, sender_id: Uuid, conversation_id: Uuid, typing: bool) {
+        let member_ids = match self.storage.get_conversation_member_ids(conversation_id).await {
+            Ok(member_ids) => member_ids,
+            Err(e) => {
+                eprintln!("Failed to load members of {}: {:?}", conversation_id, e);
+                return;
+            }
+        };
+
+        let event = if typing {
+            WebSocketEvent::UserTyping { user_id: sender_id, conversation_id }
+        } else {
+            WebSocketEvent::UserStoppedTyping { user_id: sender_id, conversation_id }
+        };
+        for member_id in member_ids.into_iter().filter(|id| *id != sender_id) {
+            self.send_to_user(member_id, &event).await;
+        }
+    }
+
+    /// Edits a message and notifies the conversation, including the editor's
+    /// other devices. `origin` is the connection the edit came from, if any,
+    /// which already knows about it.
+    /// Delivers a newly stored message to every member of its conversation,
+    /// including the sender's other devices, and logs it for replay to those
+    /// who are offline. `origin` is the connection it was sent from, if any.
+    pub async fn deliver_new_message(&self, conversation: &Conversation, message: Message, origin: Option<Uuid>) {
+        let sender_id = message.sender_id;
+        let event = WebSocketEvent::MessageReceived(message);
+        for member in &conversation.members {
+            self.send_to_user_except(member.user_id, origin, &event).await;
+        }
+        println!("Message sent to conversation members and the sender's other devices");
+        if self.typing.stop(sender_id, conversation.id) {
+            self.send_typing(sender_id, conversation.id, false).await;
+        }
+    }
+
+    pub async fn edit_message(
+        &self,
+        editor_id: Uuid,
+        message_id: Uuid,
+        content: String,
+        origin: Option<Uuid>,
+    ) -> Result<Message, StorageError> {
+        let message = self.storage.edit_message(message_id, editor_id, content).await?;
+        self.send_to_conversation(
+            message.conversation_id,
+            origin,
+            &WebSocketEvent::MessageEdited(message.clone()),
+        )
+            .await;
+
+        Ok(message)
+    }
+
+    pub async fn delete_message(
+        &self,
+        actor_id: Uuid,
+        message_id: Uuid,
+        origin: Option<Uuid>,
+    ) -> Result<Message, StorageError> {
+        let message = self.storage.delete_message(message_id, actor_id).await?;
+        self.send_to_conversation(
+            message.conversation_id,
+            origin,
+            &WebSocketEvent::MessageDeleted {
+                message_id: message.id,
+                conversation_id: message.conversation_id,
+            },
+        )
+            .await;
+
+        Ok(message)
+    }
+
+    /// Adds or removes one of the user's reactions and tells the conversation
+    /// about it. Repeating a reaction, or removing one that is not there, is
+    /// accepted quietly.
+    async fn set_reaction(
+        &self,
+        user_id: Uuid,
+        message_id: Uuid,
+        emoji: String,
+        added: bool,
+        origin: Option<Uuid>,
+    ) -> Result<(), CommandError> {
+        let emoji = emoji.trim().to_string();
+        if emoji.is_empty()
+            || emoji.chars().count() > MAX_REACTION_LENGTH
+            || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(CommandError::new(ErrorCode::InvalidCommand, "Invalid reaction"));
+        }
+
+        let update = if added {
+            self.storage.add_reaction(message_id, user_id, &emoji).await?
+        } else {
+            self.storage.remove_reaction(message_id, user_id, &emoji).await?
+        };
+        if !update.changed {
+            return Ok(());
+        }
+
+        self.send_to_conversation(
+            update.conversation_id,
+            origin,
+            &WebSocketEvent::ReactionChanged {
+                message_id,
+                conversation_id: update.conversation_id,
+                user_id,
+                emoji,
+                added,
+                count: update.count,
+            },
+        )
+            .await;
+
+        Ok(())
+    }
+
+    /// Deletes a message on a moderator's behalf and tells the conversation.
+    pub async fn remove_message(&self, message_id: Uuid) -> Result<Message, StorageError> {
+        let message = self.storage.remove_message(message_id).await?;
+        self.send_to_conversation(
+            message.conversation_id,
+            None,
+            &WebSocketEvent::MessageDeleted {
+                message_id: message.id,
+                conversation_id: message.conversation_id,
+            },
+        )
+            .await;
+
+        Ok(message)
+    }
+
+    /// Delivers an event to every connection of every member of a
+    /// conversation, optionally skipping the connection that caused it.
+    pub async fn send_to_conversation(
+        &self,
+        conversation_id: Uuid,
+        skip_connection: Option<Uuid>,
+        event: &WebSocketEvent,
+    ) {
+        let member_ids = match self.storage.get_conversation_member_ids(conversation_id).await {
+            Ok(member_ids) => member_ids,
+            Err(e) => {
+                eprintln!("Failed to load members of {}: {:?}", conversation_id, e);
+                return;
+            }
+        };
+
+        for member_id in member_ids {
+            self.send_to_user_except(member_id, skip_connection, event).await;
+        }
+    }
+
+    /// Sends everything logged for the user after `last_seq`, followed by a
+    /// `ReplayComplete` marker. Clients connecting without a sequence only get
+    /// the marker, to learn where the log currently stands.
+    async fn replay_missed_events(
+        &self,
+        user_id: Uuid,
+        last_seq: Option<i64>,
+        codec: Codec,
+        queue: &OutboundQueue,
+    ) {
+        let (mut replayed_seq, truncated) = match last_seq {
+            Some(last_seq) => {
+                let events = match self
+                    .storage
+                    .get_user_events_since(user_id, last_seq, MAX_REPLAY_EVENTS)
+                    .await
+                {
+                    Ok(events) => events,
+                    Err(e) => {
+                        eprintln!("Failed to load missed events for {}: {:?}", user_id, e);
+                        return;
+                    }
+                };
+
+                println!("Replaying {} missed events to {}", events.len(), user_id);
+                let truncated = events.len() as i64 == MAX_REPLAY_EVENTS;
+                let mut replayed_seq = last_seq;
+                for event in events {
+                    replayed_seq = event.seq;
+                    let delivers = received_message_id(&event.event);
+                    let event = EncodedEvent::from_json(sequenced_json(event.seq, event.event));
+                    push(queue, Priority::Essential, Frame { message: event.message(codec), delivers });
+                }
+                (replayed_seq, truncated)
+            }
+            None => (0, false),
+        };
+
+        if last_seq.is_none() || truncated {
+            replayed_seq = self.storage.get_latest_event_seq(user_id).await.unwrap_or(replayed_seq);
+        }
+
+        let marker = WebSocketEvent::ReplayComplete { last_seq: replayed_seq, truncated };
+        push(queue, Priority::Essential, codec.encode(&marker));
     }
 
     async fn send_to_user(&self, user_id: Uuid, event: &WebSocketEvent) {
-        if let Some(sender) = self.users.read().await.get(&user_id) {
-            let event_json = serde_json::to_string(&event).unwrap();
-            println!("Sending WebSocket message to {}: {}", user_id, event_json);
-            let _ = sender.send(Ok(WsMessage::text(event_json)));
+        self.send_to_user_except(user_id, None, event).await;
+    }
+
+    async fn send_to_user_except(
+        &self,
+        user_id: Uuid,
+        skip_connection: Option<Uuid>,
+        event: &WebSocketEvent,
+    ) {
+        let (event_json, payload) = if event.is_durable() {
+            let value = serde_json::to_value(event).unwrap();
+            match self.storage.append_user_event(user_id, &value).await {
+                Ok(seq) => (sequenced_json(seq, value), Payload::Logged { seq }),
+                Err(e) => {
+                    eprintln!("Failed to log event for {}: {:?}", user_id, e);
+                    (value.to_string(), Payload::Inline(value.to_string()))
+                }
+            }
         } else {
-            println!("User {} not connected to WebSocket", user_id);
+            let event_json = serde_json::to_string(&event).unwrap();
+            (event_json.clone(), Payload::Inline(event_json))
+        };
+
+        let priority = event.priority();
+        let delivers = event.delivers();
+        let encoded = EncodedEvent::from_json(event_json);
+        if !deliver_to_user(&self.users, user_id, skip_connection, priority, delivers, &encoded).await {
+            println!("User {} not connected to this node, event kept for replay", user_id);
         }
+        self.publish(Delivery::User { user_id, skip_connection, priority, payload, delivers }).await;
     }
 
-    async fn broadcast_user_status(&self, user_id: Uuid, online: bool) {
-        let event = if online {
-            WebSocketEvent::UserOnline(user_id)
+    /// Announces a user's status on this node to every other node, and what
+    /// that makes their visible status to everyone sharing a conversation
+    /// with them. A user who goes offline here but is still connected through
+    /// another node keeps that node's status.
+    async fn broadcast_presence(&self, user_id: Uuid, status: PresenceStatus) {
+        let visible = if status == PresenceStatus::Offline {
+            self.presence.status_of(user_id).await
+        } else {
+            status
+        };
+
+        let last_seen = if visible == PresenceStatus::Offline {
+            match self.storage.get_last_seen_records(&[user_id]).await {
+                Ok(records) => records
+                    .into_iter()
+                    .find(|record| record.show_last_seen)
+                    .map(|record| record.last_seen),
+                Err(e) => {
+                    eprintln!("Failed to load last_seen of {}: {:?}", user_id, e);
+                    None
+                }
+            }
         } else {
-            WebSocketEvent::UserOffline(user_id)
+            None
         };
 
+        let event = WebSocketEvent::PresenceChanged(Presence { user_id, status: visible, last_seen });
+        // Encoded once here and once more per codec in use, not per recipient.
+        let encoded = EncodedEvent::from_json(serde_json::to_string(&event).unwrap());
+        deliver_presence(&self.users, &self.storage, user_id, &encoded).await;
+        self.publish(Delivery::Presence {
+            user_id,
+            status,
+            event_json: encoded.as_json().to_string(),
+        })
+            .await;
+    }
+
+    /// Current presence of the requested users, limited to the viewer and the
+    /// users they share a conversation with.
+    pub async fn presence_snapshot(
+        &self,
+        viewer_id: Uuid,
+        user_ids: &[Uuid],
+    ) -> Result<Vec<Presence>, StorageError> {
+        let audience: HashSet<Uuid> = self
+            .storage
+            .get_presence_audience(viewer_id)
+            .await?
+            .into_iter()
+            .collect();
+        let visible: Vec<Uuid> = user_ids
+            .iter()
+            .copied()
+            .filter(|id| *id == viewer_id || audience.contains(id))
+            .collect();
+
+        let mut snapshot = Vec::with_capacity(visible.len());
+        for record in self.storage.get_last_seen_records(&visible).await? {
+            let status = match self.presence.chosen(record.user_id).await {
+                Some(chosen) if record.user_id == viewer_id => chosen,
+                _ => self.presence.status_of(record.user_id).await,
+            };
+            let last_seen = (status == PresenceStatus::Offline && record.show_last_seen)
+                .then_some(record.last_seen);
+            snapshot.push(Presence { user_id: record.user_id, status, last_seen });
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Outbound queue metrics of every connection on this node, slowest first.
+    pub async fn connection_stats(&self) -> Vec<ConnectionStats> {
         let users = self.users.read().await;
-        for (id, sender) in users.iter() {
-            if *id != user_id {
-                let event_json = serde_json::to_string(&event).unwrap();
-                let _ = sender.send(Ok(WsMessage::text(event_json)));
+        let mut stats: Vec<ConnectionStats> = users
+            .iter()
+            .flat_map(|(user_id, connections)| {
+                connections.iter().map(move |(connection_id, connection)| ConnectionStats {
+                    user_id: *user_id,
+                    connection_id: *connection_id,
+                    session_id: connection.session_id,
+                    capacity: connection.queue.capacity(),
+                    queue: connection.queue.stats(),
+                })
+            })
+            .collect();
+
+        stats.sort_by_key(|s| std::cmp::Reverse(s.queue.depth));
+        stats
+    }
+
+    /// Forcibly closes every live connection that was opened with one of the
+    /// given sessions, on this node and on every other one.
+    pub async fn disconnect_sessions(&self, session_ids: &[Uuid]) {
+        close_sessions(&self.users, session_ids).await;
+        self.publish(Delivery::DisconnectSessions { session_ids: session_ids.to_vec() }).await;
+    }
+
+    async fn publish(&self, delivery: Delivery) {
+        let envelope = Envelope { origin: self.node_id, delivery };
+        if let Err(e) = self.pubsub.publish(envelope).await {
+            eprintln!("Failed to publish delivery to other nodes: {}", e);
+        }
+    }
+}
+
+/// Applies deliveries published by other nodes to the connections of this
+/// one. Durable events are loaded from the event log only when the recipient
+/// is actually connected here.
+async fn run_remote_deliveries(
+    node_id: Uuid,
+    users: Users,
+    storage: Arc<Storage>,
+    presence: Arc<PresenceTracker>,
+    pubsub: Arc<dyn PubSub>,
+    mut envelopes: mpsc::UnboundedReceiver<Envelope>,
+) {
+    while let Some(envelope) = envelopes.recv().await {
+        if envelope.origin == node_id {
+            continue;
+        }
+
+        match envelope.delivery {
+            Delivery::User { user_id, skip_connection, priority, payload, delivers } => {
+                if !users.read().await.contains_key(&user_id) {
+                    continue;
+                }
+
+                let event_json = match payload {
+                    Payload::Inline(event_json) => event_json,
+                    Payload::Logged { seq } => match storage.get_user_event(user_id, seq).await {
+                        Ok(Some(event)) => sequenced_json(seq, event),
+                        Ok(None) => continue,
+                        Err(e) => {
+                            eprintln!("Failed to load event {} for {}: {:?}", seq, user_id, e);
+                            continue;
+                        }
+                    },
+                };
+                let encoded = EncodedEvent::from_json(event_json);
+                deliver_to_user(&users, user_id, skip_connection, priority, delivers, &encoded).await;
+            }
+            Delivery::Presence { user_id, status, event_json } => {
+                presence.record_remote(user_id, envelope.origin, status).await;
+
+                // A user still connected here keeps this node's status, and
+                // the other nodes may not know about this connection yet.
+                let Some(local) = presence.local_status(user_id).await else {
+                    deliver_presence(&users, &storage, user_id, &EncodedEvent::from_json(event_json)).await;
+                    continue;
+                };
+
+                let event = WebSocketEvent::PresenceChanged(Presence { user_id, status: local, last_seen: None });
+                let encoded = EncodedEvent::from_json(serde_json::to_string(&event).unwrap());
+                deliver_presence(&users, &storage, user_id, &encoded).await;
+
+                if status == PresenceStatus::Offline && local != PresenceStatus::Offline {
+                    let envelope = Envelope {
+                        origin: node_id,
+                        delivery: Delivery::Presence {
+                            user_id,
+                            status: local,
+                            event_json: encoded.as_json().to_string(),
+                        },
+                    };
+                    if let Err(e) = pubsub.publish(envelope).await {
+                        eprintln!("Failed to re-announce presence of {}: {}", user_id, e);
+                    }
+                }
+            }
+            Delivery::DisconnectSessions { session_ids } => {
+                close_sessions(&users, &session_ids).await;
+            }
+        }
+    }
+}
+
+/// Sends an event to all of a user's connections on this node except
+/// `skip_connection`. Returns whether the user has any connection here.
+async fn deliver_to_user(
+    users: &Users,
+    user_id: Uuid,
+    skip_connection: Option<Uuid>,
+    priority: Priority,
+    delivers: Option<Uuid>,
+    event: &EncodedEvent,
+) -> bool {
+    match users.read().await.get(&user_id) {
+        Some(connections) => {
+            println!("Sending WebSocket message to {} ({} connections)", user_id, connections.len());
+            for (connection_id, connection) in connections.iter() {
+                if Some(*connection_id) != skip_connection {
+                    let message = event.message(connection.codec);
+                    push(&connection.queue, priority, Frame { message, delivers });
+                }
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sends a presence event about `user_id` to the connections on this node of
+/// users who share a conversation with them.
+async fn deliver_presence(users: &Users, storage: &Storage, user_id: Uuid, event: &EncodedEvent) {
+    if users.read().await.is_empty() {
+        return;
+    }
+
+    let audience = match storage.get_presence_audience(user_id).await {
+        Ok(audience) => audience,
+        Err(e) => {
+            eprintln!("Failed to load presence audience of {}: {:?}", user_id, e);
+            return;
+        }
+    };
+
+    let users = users.read().await;
+    for member_id in audience {
+        if let Some(connections) = users.get(&member_id) {
+            for connection in connections.values() {
+                push(&connection.queue, Priority::Droppable, event.message(connection.codec));
             }
         }
     }
-}
\ No newline at end of file
+}
+
+async fn close_sessions(users: &Users, session_ids: &[Uuid]) {
+    let mut users = users.write().await;
+    for connections in users.values_mut() {
+        let revoked: Vec<Uuid> = connections
+            .iter()
+            .filter(|(_, connection)| session_ids.contains(&connection.session_id))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for connection_id in revoked {
+            if let Some(connection) = connections.remove(&connection_id) {
+                let _ = connection.close.send(());
+            }
+        }
+    }
+    users.retain(|_, connections| !connections.is_empty());
+}
+
+/// Queues a frame, applying the slow-consumer policy. A connection whose
+/// queue gets closed is torn down by its own loop once the writer stops;
+/// anything durable it missed comes back through replay on reconnect.
+fn push(queue: &OutboundQueue, priority: Priority, frame: impl Into<Frame>) {
+    if queue.push(priority, frame) == PushResult::Disconnect {
+        println!("Disconnecting slow WebSocket consumer with a full outbound queue");
+    }
+}
+
+/// Tags a logged event with its sequence number, e.g.
+/// `{"seq": 42, "MessageReceived": {...}}`.
+fn sequenced_json(seq: i64, mut event: serde_json::Value) -> String {
+    if let Some(object) = event.as_object_mut() {
+        object.insert("seq".to_string(), serde_json::Value::from(seq));
+    }
+    event.to_string()
+}
+
+/// The message id of a logged `MessageReceived` event.
+fn received_message_id(event: &serde_json::Value) -> Option<Uuid> {
+    event.get("MessageReceived")?.get("id")?.as_str()?.parse().ok()
+}
+
+fn group_by_sender(updates: Vec<ReceiptUpdate>) -> HashMap<Uuid, Vec<Uuid>> {
+    let mut grouped: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for update in updates {
+        grouped.entry(update.sender_id).or_default().push(update.message_id);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime_messenger::pubsub::InMemoryPubSub;
+    use sqlx::postgres::PgPoolOptions;
+
+    /// A handler whose storage never connects; the events used here are
+    /// delivered without touching the database.
+    fn handler(pubsub: Arc<dyn PubSub>) -> WebSocketHandler {
+        let db_pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        let storage = Storage::new(db_pool, std::env::temp_dir().join("messenger-tests"));
+        WebSocketHandler::new(storage, pubsub, WebSocketConfig::default())
+    }
+
+    async fn connect(handler: &WebSocketHandler, user_id: Uuid) -> Arc<OutboundQueue> {
+        let queue = Arc::new(OutboundQueue::new(8, 1));
+        let (close, _) = oneshot::channel();
+        let connection = Connection {
+            session_id: Uuid::new_v4(),
+            codec: Codec::Json,
+            queue: queue.clone(),
+            close,
+        };
+        handler.users.write().await.entry(user_id).or_default().insert(Uuid::new_v4(), connection);
+        queue
+    }
+
+    async fn next_frame(queue: &OutboundQueue) -> Option<String> {
+        tokio::time::timeout(Duration::from_millis(200), queue.pop())
+            .await
+            .ok()
+            .flatten()
+            .map(|frame| frame.message.to_str().unwrap().to_string())
+    }
+
+    fn typing_event() -> WebSocketEvent {
+        WebSocketEvent::UserTyping { user_id: Uuid::new_v4(), conversation_id: Uuid::new_v4() }
+    }
+
+    #[tokio::test]
+    async fn events_reach_users_connected_to_another_node() {
+        let pubsub: Arc<dyn PubSub> = Arc::new(InMemoryPubSub::new());
+        let first = handler(pubsub.clone());
+        let second = handler(pubsub);
+        let user_id = Uuid::new_v4();
+        let queue = connect(&second, user_id).await;
+
+        let event = typing_event();
+        first.send_to_user(user_id, &event).await;
+
+        assert_eq!(next_frame(&queue).await, Some(serde_json::to_string(&event).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn nodes_do_not_deliver_their_own_envelopes_twice() {
+        let pubsub: Arc<dyn PubSub> = Arc::new(InMemoryPubSub::new());
+        let first = handler(pubsub.clone());
+        let _second = handler(pubsub);
+        let user_id = Uuid::new_v4();
+        let queue = connect(&first, user_id).await;
+
+        first.send_to_user(user_id, &typing_event()).await;
+
+        assert!(next_frame(&queue).await.is_some());
+        assert_eq!(next_frame(&queue).await, None);
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_are_closed_on_every_node() {
+        let pubsub: Arc<dyn PubSub> = Arc::new(InMemoryPubSub::new());
+        let first = handler(pubsub.clone());
+        let second = handler(pubsub);
+        let user_id = Uuid::new_v4();
+        connect(&second, user_id).await;
+        let session_id = second.users.read().await[&user_id].values().next().unwrap().session_id;
+
+        first.disconnect_sessions(&[session_id]).await;
+
+        tokio::time::timeout(Duration::from_millis(200), async {
+            while second.users.read().await.contains_key(&user_id) {
+                tokio::task::yield_now().await;
+            }
+        })
+            .await
+            .expect("session still connected on the other node");
+    }
+}